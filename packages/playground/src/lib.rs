@@ -1,17 +1,35 @@
 use wasm_bindgen::prelude::*;
 use web_sys::{OffscreenCanvas, WebGlRenderingContext};
 
-use crate::simulations::{Boid, FallingSand, Flock, GoL, Simulation};
+use crate::simulations::{
+    Behaviour, Boid, BoundaryMode, FallingSand, Flock, Flock3D, FlockMetrics, GoL, Interaction,
+    NeighbourMode, Obstacle, Path, Perception, SandStats, Simulation, Species, VectorField, Wander,
+};
 // use crate::simulations::GoL;
 
 type GL = web_sys::WebGlRenderingContext;
 
+/// Starts `threads` web workers for the thread pool. Needs `SharedArrayBuffer`,
+/// so the page has to be cross origin isolated. Parallel stepping can be
+/// turned on once the returned promise resolves.
+#[cfg(feature = "wasm-threads")]
+#[wasm_bindgen(js_name = startThreadPool)]
+pub fn start_thread_pool(threads: usize) -> js_sys::Promise {
+    let ready = Closure::wrap(
+        Box::new(|_| utils::thread_pool::mark_ready()) as Box<dyn FnMut(JsValue)>
+    );
+    let promise = wasm_bindgen_rayon::init_thread_pool(threads).then(&ready);
+    ready.forget();
+    promise
+}
+
 mod common_funcs;
 mod gl_setup;
-mod quadtree;
+pub mod quadtree;
 mod rendering;
 mod shaders;
 mod simulations;
+mod uniform_grid;
 mod utils;
 
 #[wasm_bindgen]
@@ -24,6 +42,7 @@ extern "C" {
 pub struct FolioClient {
     gl: WebGlRenderingContext,
     flock: Flock,
+    flock3d: Flock3D,
     fallingsim: FallingSand,
     golsim: GoL,
     n: u16,
@@ -37,13 +56,15 @@ impl FolioClient {
         let width = gl.drawing_buffer_width();
         let height = gl.drawing_buffer_height();
         let gol = GoL::new(&gl, width as u32 / 10, height as u32 / 10);
-        let fs = FallingSand::new(&gl, width as u32 / 10, height as u32 / 10);
+        let fs = FallingSand::new(&gl, width as u32, height as u32);
         //*****let flock = Flock::new(&gl, canvas.width() / 10, canvas.height() / 10);
         let flock = Flock::new(&gl, width as u32, height as u32);
+        let flock3d = Flock3D::new(&gl, width as u32, height as u32);
 
         Self {
             gl,
             flock: flock,
+            flock3d,
             fallingsim: fs,
             golsim: gol,
             n,
@@ -61,11 +82,442 @@ impl FolioClient {
                 self.gl.drawing_buffer_width(),
                 self.gl.drawing_buffer_height(),
             ),
+            3 => self.flock3d.update(
+                self.gl.drawing_buffer_width(),
+                self.gl.drawing_buffer_height(),
+            ),
             _ => println!("err"),
         }
         Ok(())
     }
 
+    /// Steps the flock and the game of life on the thread pool. Only has an
+    /// effect in builds with threads, after `startThreadPool` has resolved.
+    pub fn set_parallel(&mut self, parallel: bool) {
+        self.flock.set_parallel(parallel);
+        self.golsim.set_parallel(parallel);
+    }
+
+    /// Spawns a boid at a point in canvas pixels, origin at the bottom left, and
+    /// returns its index.
+    pub fn spawn_boid(&mut self, x: f32, y: f32, vx: f32, vy: f32, species: usize) -> usize {
+        self.flock.spawn(
+            cgmath::Vector2::new(x, y),
+            cgmath::Vector2::new(vx, vy),
+            species,
+        )
+    }
+
+    /// Removes every boid within `radius` pixels of the point, returns how many.
+    pub fn remove_boids(&mut self, x: f32, y: f32, radius: f32) -> usize {
+        self.flock
+            .remove_in_radius(cgmath::Vector2::new(x, y), radius)
+    }
+
+    pub fn set_boid_count(&mut self, count: usize) {
+        self.flock.resize(count);
+    }
+
+    pub fn boid_count(&self) -> usize {
+        self.flock.len()
+    }
+
+    /// Registers a boid species and returns its id.
+    pub fn add_species(
+        &mut self,
+        r: f32,
+        g: f32,
+        b: f32,
+        a: f32,
+        size: f32,
+        max_speed: f32,
+    ) -> usize {
+        self.flock.add_species(Species {
+            color: [r, g, b, a],
+            size,
+            max_speed,
+        })
+    }
+
+    pub fn set_interaction(&mut self, a: usize, b: usize, interaction: Interaction) {
+        self.flock.set_interaction(a, b, interaction);
+    }
+
+    pub fn assign_species(&mut self, species: usize, count: usize) {
+        self.flock.assign_species(species, count);
+    }
+
+    pub fn hawks_and_starlings(&mut self, hawks: usize) {
+        self.flock.hawks_and_starlings(hawks);
+    }
+
+    /// Radii are in pixels, `blind_angle` is the full angle in radians of the
+    /// cone behind each boid it can't see into.
+    pub fn set_perception(
+        &mut self,
+        alignment_radius: f32,
+        cohesion_radius: f32,
+        seperation_radius: f32,
+        blind_angle: f32,
+    ) {
+        self.flock.set_perception(Perception {
+            alignment_radius,
+            cohesion_radius,
+            seperation_radius,
+            blind_angle,
+        });
+    }
+
+    /// Polarization, milling, speed, spacing and cluster count of the flock's
+    /// last step, for tuning parameters by the numbers.
+    pub fn flock_metrics(&self) -> FlockMetrics {
+        self.flock.metrics()
+    }
+
+    /// Draws a fading line behind every boid through its last `length`
+    /// positions, each step older loses `fade` of the opacity. 0 turns it off.
+    pub fn set_trails(&mut self, length: usize, fade: f32) {
+        self.flock.set_trails(length, fade);
+    }
+
+    /// `Topological` makes boids react to their `count` nearest neighbours
+    /// instead of everyone within the perception radius.
+    pub fn set_neighbour_mode(&mut self, mode: NeighbourMode, count: usize) {
+        self.flock.set_neighbour_mode(mode);
+        self.flock.set_neighbour_count(count);
+    }
+
+    /// Stops boids of `radius` pixels from overlapping, looking
+    /// `time_horizon` steps ahead for collisions. A radius of 0 turns it off.
+    pub fn set_collision_avoidance(&mut self, radius: f32, time_horizon: f32) {
+        self.flock.set_collision_avoidance(radius, time_horizon);
+    }
+
+    pub fn set_boundary_mode(&mut self, mode: BoundaryMode) {
+        self.flock.set_boundary_mode(mode);
+    }
+
+    /// Width in pixels of the band along the edges where `Steer` turns boids.
+    pub fn set_boundary_margin(&mut self, margin: f32) {
+        self.flock.set_boundary_margin(margin);
+    }
+
+    /// Steering behaviours are applied to every boid, scaled by `weight`, and
+    /// return an id for `remove_behaviour`. Positions are in canvas pixels with
+    /// the origin at the bottom left.
+    pub fn add_seek_behaviour(&mut self, x: f32, y: f32, weight: f32) -> usize {
+        let target = cgmath::Vector2::new(x, y);
+        self.flock.add_behaviour(Behaviour::Seek(target), weight)
+    }
+
+    pub fn add_flee_behaviour(&mut self, x: f32, y: f32, weight: f32) -> usize {
+        let threat = cgmath::Vector2::new(x, y);
+        self.flock.add_behaviour(Behaviour::Flee(threat), weight)
+    }
+
+    pub fn add_arrive_behaviour(
+        &mut self,
+        x: f32,
+        y: f32,
+        slowing_radius: f32,
+        weight: f32,
+    ) -> usize {
+        let target = cgmath::Vector2::new(x, y);
+        self.flock.add_behaviour(
+            Behaviour::Arrive {
+                target,
+                slowing_radius,
+            },
+            weight,
+        )
+    }
+
+    pub fn add_wander_behaviour(
+        &mut self,
+        distance: f32,
+        radius: f32,
+        jitter: f32,
+        weight: f32,
+    ) -> usize {
+        self.flock.add_behaviour(
+            Behaviour::Wander(Wander {
+                distance,
+                radius,
+                jitter,
+            }),
+            weight,
+        )
+    }
+
+    pub fn add_pursue_behaviour(&mut self, target: usize, weight: f32) -> usize {
+        self.flock.add_behaviour(Behaviour::Pursue(target), weight)
+    }
+
+    pub fn add_evade_behaviour(&mut self, threat: usize, weight: f32) -> usize {
+        self.flock.add_behaviour(Behaviour::Evade(threat), weight)
+    }
+
+    /// `points` is a flat list of x, y pairs.
+    pub fn add_path_behaviour(&mut self, points: Vec<f32>, radius: f32, weight: f32) -> usize {
+        let points = points
+            .chunks(2)
+            .filter(|point| point.len() == 2)
+            .map(|point| cgmath::Vector2::new(point[0], point[1]))
+            .collect();
+        self.flock
+            .add_behaviour(Behaviour::FollowPath(Path { points, radius }), weight)
+    }
+
+    pub fn add_leader_behaviour(&mut self, leader: usize, distance: f32, weight: f32) -> usize {
+        self.flock
+            .add_behaviour(Behaviour::FollowLeader { leader, distance }, weight)
+    }
+
+    pub fn remove_behaviour(&mut self, id: usize) -> bool {
+        self.flock.remove_behaviour(id)
+    }
+
+    pub fn clear_behaviours(&mut self) {
+        self.flock.clear_behaviours();
+    }
+
+    /// Obstacles are in canvas pixels with the origin at the bottom left.
+    pub fn add_circle_obstacle(&mut self, x: f32, y: f32, radius: f32) -> usize {
+        self.flock.add_obstacle(Obstacle::Circle { x, y, radius })
+    }
+
+    pub fn add_rectangle_obstacle(&mut self, x: f32, y: f32, width: f32, height: f32) -> usize {
+        self.flock.add_obstacle(Obstacle::Rectangle {
+            x,
+            y,
+            width,
+            height,
+        })
+    }
+
+    /// `points` is a flat list of x, y pairs.
+    pub fn add_polygon_obstacle(&mut self, points: Vec<f32>) -> usize {
+        self.flock.add_obstacle(Obstacle::Polygon(
+            points
+                .chunks(2)
+                .filter(|point| point.len() == 2)
+                .map(|point| cgmath::Vector2::new(point[0], point[1]))
+                .collect(),
+        ))
+    }
+
+    pub fn remove_obstacle(&mut self, id: usize) -> bool {
+        self.flock.remove_obstacle(id)
+    }
+
+    pub fn clear_obstacles(&mut self) {
+        self.flock.clear_obstacles();
+    }
+
+    /// Vector fields push every boid, scaled by `weight`, and return an id for
+    /// `remove_field`. Positions are in canvas pixels, origin at the bottom left.
+    pub fn add_wind_field(&mut self, x: f32, y: f32, gust: f32, weight: f32) -> usize {
+        let wind = cgmath::Vector2::new(x, y);
+        self.flock.add_field(VectorField::Uniform { wind, gust }, weight)
+    }
+
+    pub fn add_vortex_field(
+        &mut self,
+        x: f32,
+        y: f32,
+        strength: f32,
+        radius: f32,
+        weight: f32,
+    ) -> usize {
+        let center = cgmath::Vector2::new(x, y);
+        self.flock.add_field(
+            VectorField::Vortex {
+                center,
+                strength,
+                radius,
+            },
+            weight,
+        )
+    }
+
+    pub fn add_noise_field(&mut self, scale: f32, strength: f32, speed: f32, weight: f32) -> usize {
+        self.flock.add_field(
+            VectorField::Noise {
+                scale,
+                strength,
+                speed,
+            },
+            weight,
+        )
+    }
+
+    /// An empty grid over the canvas to draw on with `paint_field`.
+    pub fn add_grid_field(&mut self, cell: f32, weight: f32) -> usize {
+        let grid = VectorField::grid(
+            self.gl.drawing_buffer_width() as u32,
+            self.gl.drawing_buffer_height() as u32,
+            cell,
+        );
+        self.flock.add_field(grid, weight)
+    }
+
+    pub fn paint_field(
+        &mut self,
+        id: usize,
+        x: f32,
+        y: f32,
+        vx: f32,
+        vy: f32,
+        radius: f32,
+    ) -> bool {
+        self.flock.paint_field(
+            id,
+            cgmath::Vector2::new(x, y),
+            cgmath::Vector2::new(vx, vy),
+            radius,
+        )
+    }
+
+    pub fn remove_field(&mut self, id: usize) -> bool {
+        self.flock.remove_field(id)
+    }
+
+    pub fn clear_fields(&mut self) {
+        self.flock.clear_fields();
+    }
+
+    /// Draws a grid of arrows showing the combined fields.
+    pub fn show_fields(&mut self, show: bool) {
+        self.flock.show_fields(show);
+    }
+
+    /// Turns the 3D flock's camera around the middle of its box, in radians.
+    pub fn orbit_camera(&mut self, yaw: f32, pitch: f32) {
+        self.flock3d.orbit_camera(yaw, pitch);
+    }
+
+    pub fn set_camera_distance(&mut self, distance: f32) {
+        self.flock3d.set_camera_distance(distance);
+    }
+
+    /// Adds a falling sand material from a JSON description, or replaces the
+    /// one with the same name, and returns its tile id. Throws if the JSON
+    /// doesn't describe a material.
+    pub fn add_sand_material(&mut self, json: &str) -> Result<u8, JsValue> {
+        self.fallingsim
+            .add_material_json(json)
+            .map_err(|error| JsValue::from_str(&error))
+    }
+
+    /// Empties the falling sand world.
+    pub fn clear_sand(&mut self) {
+        self.fallingsim.clear();
+    }
+
+    /// Checks the falling sand tile storage after every step and throws if
+    /// it's broken. On by default in debug builds.
+    pub fn set_sand_debug(&mut self, debug: bool) {
+        self.fallingsim.set_debug(debug);
+    }
+
+    /// Tiles, awake chunks and how many cells changed in each chunk during the
+    /// falling sand world's last step.
+    pub fn sand_stats(&self) -> SandStats {
+        self.fallingsim.stats()
+    }
+
+    /// Sets which way and how hard falling sand tiles fall, in cells a step
+    /// per step, so a device's tilt can tip the world over.
+    pub fn set_sand_gravity(&mut self, x: f32, y: f32) {
+        self.fallingsim.set_gravity(x, y);
+    }
+
+    /// Caps how fast falling sand tiles fall, in cells a step.
+    pub fn set_sand_terminal_velocity(&mut self, speed: f32) {
+        self.fallingsim.set_terminal_velocity(speed);
+    }
+
+    /// Lets falling sand liquids push up through the bodies they're in, so
+    /// joined basins level out. On by default.
+    pub fn set_sand_pressure(&mut self, pressure: bool) {
+        self.fallingsim.set_pressure(pressure);
+    }
+
+    /// Tints the falling sand world by temperature, blue for cold and red
+    /// through white for hot.
+    pub fn show_sand_heat(&mut self, show: bool) {
+        self.fallingsim.show_heat(show);
+    }
+
+    /// Fills the empty falling sand cells within `radius` cells of a cell with
+    /// a material, by name, or clears them for `"empty"`. Cells count from the
+    /// bottom left.
+    pub fn paint_sand(
+        &mut self,
+        x: i32,
+        y: i32,
+        radius: i32,
+        material: &str,
+    ) -> Result<(), JsValue> {
+        let id = self
+            .fallingsim
+            .material_id(material)
+            .ok_or_else(|| JsValue::from_str(&format!("unknown material `{}`", material)))?;
+        self.fallingsim.paint(x, y, radius, id);
+        Ok(())
+    }
+
+    /// Flings the falling sand tiles that aren't solid within `radius` cells
+    /// of a cell out of the grid, away from it at up to `strength` cells a
+    /// step. They land back in the grid wherever they come down.
+    pub fn explode_sand(&mut self, x: i32, y: i32, radius: i32, strength: f32) {
+        self.fallingsim.explode(x, y, radius, strength);
+    }
+
+    /// Makes two falling sand materials react when they touch, described in
+    /// JSON like `{"between": ["lava", "water"], "into": ["stone", "steam"],
+    /// "chance": 0.5}`.
+    pub fn add_sand_reaction(&mut self, json: &str) -> Result<(), JsValue> {
+        self.fallingsim
+            .add_reaction_json(json)
+            .map_err(|error| JsValue::from_str(&error))
+    }
+
+    /// Adds a falling sand emitter described in JSON like `{"material":
+    /// "sand", "x": 40, "y": 90, "shape": {"type": "circle", "radius": 2},
+    /// "rate": 0.5, "spread": 1, "lifetime": 600}` and returns its id. Only
+    /// the material and position are needed. Throws for unknown materials.
+    pub fn add_sand_emitter(&mut self, json: &str) -> Result<usize, JsValue> {
+        self.fallingsim
+            .add_emitter_json(json)
+            .map_err(|error| JsValue::from_str(&error))
+    }
+
+    pub fn remove_sand_emitter(&mut self, id: usize) -> bool {
+        self.fallingsim.remove_emitter(id)
+    }
+
+    /// Adds a falling sand sink, which deletes every tile reaching it,
+    /// described in JSON like `{"x": 60, "y": 0, "shape": {"type":
+    /// "rectangle", "width": 8, "height": 2}}`, and returns its id.
+    pub fn add_sand_sink(&mut self, json: &str) -> Result<usize, JsValue> {
+        self.fallingsim
+            .add_sink_json(json)
+            .map_err(|error| JsValue::from_str(&error))
+    }
+
+    pub fn remove_sand_sink(&mut self, id: usize) -> bool {
+        self.fallingsim.remove_sink(id)
+    }
+
+    /// Replaces the falling sand emitters and sinks with a scene's, written
+    /// like `{"emitters": [...], "sinks": [...]}`.
+    pub fn load_sand_scene(&mut self, json: &str) -> Result<(), JsValue> {
+        self.fallingsim
+            .load_scene_json(json)
+            .map_err(|error| JsValue::from_str(&error))
+    }
+
     pub fn render(&self) {
         self.gl.viewport(
             0,
@@ -79,6 +531,7 @@ impl FolioClient {
             0 => self.flock.render(&self.gl),
             1 => self.fallingsim.render(&self.gl),
             2 => self.golsim.render(&self.gl),
+            3 => self.flock3d.render(&self.gl),
             _ => println!("err"),
         }
 
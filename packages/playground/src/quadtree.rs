@@ -5,7 +5,11 @@ use cgmath::prelude::*;
 use crate::simulations::Boid;
 use web_sys::{WebGlBuffer, WebGlProgram, WebGlRenderingContext as GL, WebGlUniformLocation};
 
-#[derive(Debug, Clone)]
+// nodes this small take points past their capacity rather than splitting, so
+// points stacked on one spot don't split the node until its children vanish
+const MIN_NODE_SIZE: f32 = 0.01;
+
+#[derive(Debug, Clone, Copy)]
 pub struct Rectangle {
     pub x: f32,
     pub y: f32,
@@ -22,9 +26,9 @@ impl Rectangle {
     }
 
     pub fn intersectCircle(&self, circle: (f32, f32, f32)) -> bool {
-        //x,y,radius
-        let x = (circle.0 - self.x).abs();
-        let y = (circle.1 - self.y).abs();
+        //x,y,radius, distances are from the center since x,y is the corner
+        let x = (circle.0 - (self.x + self.width / 2.0)).abs();
+        let y = (circle.1 - (self.y + self.height / 2.0)).abs();
 
         if x > self.width / 2.0 + circle.2 {
             return false;
@@ -44,7 +48,45 @@ impl Rectangle {
 
         return corner_dist <= circle.2.powi(2);
     }
+
+    /// Squared distance from a point to the nearest point of the rectangle.
+    /// With a `period` distances wrap around a torus of that width and height.
+    pub fn distance2(&self, point: (f32, f32), period: Option<(f32, f32)>) -> f32 {
+        let dx = axis_distance(point.0, self.x, self.x + self.width, period.map(|p| p.0));
+        let dy = axis_distance(point.1, self.y, self.y + self.height, period.map(|p| p.1));
+        dx * dx + dy * dy
+    }
+
+    pub fn intersects(&self, other: &Rectangle) -> bool {
+        self.x < other.x + other.width
+            && other.x < self.x + self.width
+            && self.y < other.y + other.height
+            && other.y < self.y + self.height
+    }
+}
+// distance from `value` to the span `low..high`, the short way around when the
+// axis wraps every `period`
+fn axis_distance(value: f32, low: f32, high: f32, period: Option<f32>) -> f32 {
+    if low <= value && value <= high {
+        return 0.0;
+    }
+    let around = |distance: f32| match period {
+        Some(period) => {
+            let distance = distance.rem_euclid(period);
+            distance.min(period - distance)
+        }
+        None => distance.abs(),
+    };
+    around(value - low).min(around(value - high))
+}
+
+/// Squared distance between two points, wrapping like `Rectangle::distance2`.
+pub fn point_distance2(a: (f32, f32), b: (f32, f32), period: Option<(f32, f32)>) -> f32 {
+    let dx = axis_distance(a.0, b.0, b.0, period.map(|p| p.0));
+    let dy = axis_distance(a.1, b.1, b.1, period.map(|p| p.1));
+    dx * dx + dy * dy
 }
+
 pub struct Quadtree {
     rectangle: Rectangle,
     capacity: i16,
@@ -153,7 +195,9 @@ impl Quadtree {
             let _ = self.points.as_mut().unwrap().push((position, boid_index));
 
             true
-        } else if self.points.as_ref().unwrap().len() < self.capacity as usize {
+        } else if self.points.as_ref().unwrap().len() < self.capacity as usize
+            || self.rectangle.width.min(self.rectangle.height) < MIN_NODE_SIZE
+        {
             let _ = self.points.as_mut().unwrap().push((position, boid_index));
 
             true
@@ -177,17 +221,24 @@ impl Quadtree {
 
     pub fn query(&self, circle: (f32, f32, f32)) -> Vec<usize> {
         let mut found: Vec<usize> = Vec::new();
+        self.query_into(circle, &mut found);
+        found
+    }
+
+    /// Like `query` but appends to `found` so callers can reuse one buffer.
+    pub fn query_into(&self, circle: (f32, f32, f32), found: &mut Vec<usize>) {
+        if !self.rectangle.intersectCircle(circle) {
+            return;
+        }
         if self.divided {
-            found.extend(self.ne.as_ref().unwrap().query(circle));
-            found.extend(self.nw.as_ref().unwrap().query(circle));
-            found.extend(self.se.as_ref().unwrap().query(circle));
-            found.extend(self.sw.as_ref().unwrap().query(circle));
+            self.ne.as_ref().unwrap().query_into(circle, found);
+            self.nw.as_ref().unwrap().query_into(circle, found);
+            self.se.as_ref().unwrap().query_into(circle, found);
+            self.sw.as_ref().unwrap().query_into(circle, found);
         }
 
-        if self.points.is_none() || !self.rectangle.intersectCircle(circle) {
-            return found;
-        } else {
-            for point in self.points.as_ref().unwrap() {
+        if let Some(points) = &self.points {
+            for point in points {
                 if ((point.0.x - circle.0).powi(2) + (point.0.y - circle.1).powi(2)).sqrt()
                     <= circle.2
                 {
@@ -195,7 +246,76 @@ impl Quadtree {
                 }
             }
         }
-        return found;
+    }
+
+    /// The `k` points closest to `point` as (squared distance, index), nearest
+    /// first. Ties are broken by index so the answer doesn't depend on the
+    /// tree's layout. With a `period` distances wrap around a torus.
+    pub fn nearest_into(
+        &self,
+        point: (f32, f32),
+        k: usize,
+        period: Option<(f32, f32)>,
+        found: &mut Vec<(f32, usize)>,
+    ) {
+        found.clear();
+        if k > 0 {
+            self.nearest_node(point, k, period, found);
+        }
+    }
+
+    fn nearest_node(
+        &self,
+        point: (f32, f32),
+        k: usize,
+        period: Option<(f32, f32)>,
+        found: &mut Vec<(f32, usize)>,
+    ) {
+        // nothing in here can beat the k we already have
+        if found.len() == k && self.rectangle.distance2(point, period) > found[k - 1].0 {
+            return;
+        }
+
+        if let Some(points) = &self.points {
+            for &(position, index) in points {
+                let candidate = (
+                    point_distance2(point, (position.x, position.y), period),
+                    index,
+                );
+                let at = found
+                    .binary_search_by(|other| {
+                        other
+                            .0
+                            .partial_cmp(&candidate.0)
+                            .unwrap_or(std::cmp::Ordering::Equal)
+                            .then(other.1.cmp(&candidate.1))
+                    })
+                    .unwrap_or_else(|at| at);
+                if at < k {
+                    found.insert(at, candidate);
+                    found.truncate(k);
+                }
+            }
+        }
+
+        if self.divided {
+            // closest child first so the others are more likely to be pruned
+            let mut children = [
+                self.nw.as_ref().unwrap(),
+                self.ne.as_ref().unwrap(),
+                self.sw.as_ref().unwrap(),
+                self.se.as_ref().unwrap(),
+            ];
+            children.sort_by(|a, b| {
+                a.rectangle
+                    .distance2(point, period)
+                    .partial_cmp(&b.rectangle.distance2(point, period))
+                    .unwrap_or(std::cmp::Ordering::Equal)
+            });
+            for child in children.iter() {
+                child.nearest_node(point, k, period, found);
+            }
+        }
     }
 
     pub fn set_dimensions(&mut self, width: f32, height: f32) {
@@ -298,3 +418,384 @@ impl Quadtree {
         }
     }
 }
+
+/// Shape of an object stored in a `LooseQuadtree`. Rectangles are anchored at
+/// their bottom left corner like `Rectangle`, circles are (x, y, radius).
+#[derive(Debug, Clone, Copy)]
+pub enum Bounds {
+    Rect(Rectangle),
+    Circle(f32, f32, f32),
+}
+
+impl Bounds {
+    pub fn center(&self) -> (f32, f32) {
+        match *self {
+            Bounds::Rect(rect) => (rect.x + rect.width / 2.0, rect.y + rect.height / 2.0),
+            Bounds::Circle(x, y, _) => (x, y),
+        }
+    }
+
+    pub fn aabb(&self) -> Rectangle {
+        match *self {
+            Bounds::Rect(rect) => rect,
+            Bounds::Circle(x, y, radius) => Rectangle {
+                x: x - radius,
+                y: y - radius,
+                width: radius * 2.0,
+                height: radius * 2.0,
+            },
+        }
+    }
+
+    pub fn intersects(&self, other: &Bounds) -> bool {
+        match (*self, *other) {
+            (Bounds::Rect(a), Bounds::Rect(b)) => a.intersects(&b),
+            (Bounds::Circle(ax, ay, ar), Bounds::Circle(bx, by, br)) => {
+                (ax - bx).powi(2) + (ay - by).powi(2) < (ar + br).powi(2)
+            }
+            (Bounds::Rect(rect), Bounds::Circle(x, y, radius))
+            | (Bounds::Circle(x, y, radius), Bounds::Rect(rect)) => {
+                // closest point on the rectangle to the circle center
+                let cx = x.max(rect.x).min(rect.x + rect.width);
+                let cy = y.max(rect.y).min(rect.y + rect.height);
+                (x - cx).powi(2) + (y - cy).powi(2) < radius.powi(2)
+            }
+        }
+    }
+}
+
+/// Quadtree for objects with extent. Every node's bounds are loosened to twice
+/// its size so an object only has to fit by size, not by position, to move into
+/// a child. Each object lives in exactly one node, which keeps broad-phase pair
+/// enumeration free of duplicates.
+pub struct LooseQuadtree {
+    rectangle: Rectangle,
+    capacity: usize,
+    depth: u8,
+    max_depth: u8,
+    objects: Vec<(Bounds, usize)>,
+    children: Option<Box<[LooseQuadtree; 4]>>,
+}
+
+impl LooseQuadtree {
+    pub fn new(capacity: usize, max_depth: u8, rectangle: Rectangle) -> Self {
+        Self::with_depth(capacity, 0, max_depth, rectangle)
+    }
+
+    fn with_depth(capacity: usize, depth: u8, max_depth: u8, rectangle: Rectangle) -> Self {
+        Self {
+            rectangle,
+            capacity,
+            depth,
+            max_depth,
+            objects: Vec::new(),
+            children: None,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.objects.clear();
+        self.children = None;
+    }
+
+    pub fn len(&self) -> usize {
+        let mut count = self.objects.len();
+        if let Some(children) = self.children.as_ref() {
+            for child in children.iter() {
+                count += child.len();
+            }
+        }
+        count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // the tight bounds grown by half their size on every side
+    fn loose_rectangle(&self) -> Rectangle {
+        Rectangle {
+            x: self.rectangle.x - self.rectangle.width / 2.0,
+            y: self.rectangle.y - self.rectangle.height / 2.0,
+            width: self.rectangle.width * 2.0,
+            height: self.rectangle.height * 2.0,
+        }
+    }
+
+    // objects fit a child when they are no bigger than half of it and their
+    // center is in this node, the child's loose bounds then always contain
+    // them. Only the root can be handed objects centered outside it.
+    fn fits_child(&self, bounds: &Bounds) -> bool {
+        let aabb = bounds.aabb();
+        let (x, y) = bounds.center();
+        self.depth < self.max_depth
+            && aabb.width <= self.rectangle.width / 4.0
+            && aabb.height <= self.rectangle.height / 4.0
+            && self.rectangle.contains(cgmath::Vector2::new(x, y))
+    }
+
+    fn child_index(&self, bounds: &Bounds) -> usize {
+        let (x, y) = bounds.center();
+        let east = x >= self.rectangle.x + self.rectangle.width / 2.0;
+        let north = y >= self.rectangle.y + self.rectangle.height / 2.0;
+        (east as usize) | ((north as usize) << 1)
+    }
+
+    fn subdivide(&mut self) {
+        let width = self.rectangle.width / 2.0;
+        let height = self.rectangle.height / 2.0;
+        let (x, y) = (self.rectangle.x, self.rectangle.y);
+        let child = |x: f32, y: f32| {
+            LooseQuadtree::with_depth(
+                self.capacity,
+                self.depth + 1,
+                self.max_depth,
+                Rectangle {
+                    x,
+                    y,
+                    width,
+                    height,
+                },
+            )
+        };
+        // sw, se, nw, ne to match child_index
+        self.children = Some(Box::new([
+            child(x, y),
+            child(x + width, y),
+            child(x, y + height),
+            child(x + width, y + height),
+        ]));
+
+        // push down whatever is small enough now that there is somewhere to go
+        let objects = std::mem::take(&mut self.objects);
+        for (bounds, index) in objects {
+            self.insert(bounds, index);
+        }
+    }
+
+    /// Indices are expected to be unique. Objects outside the root are kept in the
+    /// root so they still collide.
+    pub fn insert(&mut self, bounds: Bounds, index: usize) {
+        if self.fits_child(&bounds) {
+            if self.children.is_none() && self.objects.len() >= self.capacity {
+                self.subdivide();
+            }
+            let child = self.child_index(&bounds);
+            if let Some(children) = self.children.as_mut() {
+                children[child].insert(bounds, index);
+                return;
+            }
+        }
+        self.objects.push((bounds, index));
+    }
+
+    pub fn remove(&mut self, index: usize) -> bool {
+        if let Some(position) = self.objects.iter().position(|object| object.1 == index) {
+            self.objects.swap_remove(position);
+            return true;
+        }
+        if let Some(children) = self.children.as_mut() {
+            for child in children.iter_mut() {
+                if child.remove(index) {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    pub fn query(&self, bounds: &Bounds) -> Vec<usize> {
+        let mut found = Vec::new();
+        self.query_into(bounds, &mut found);
+        found
+    }
+
+    /// Like `query` but appends to `found` so callers can reuse one buffer.
+    pub fn query_into(&self, bounds: &Bounds, found: &mut Vec<usize>) {
+        self.query_node(bounds, &bounds.aabb(), found);
+    }
+
+    fn query_node(&self, bounds: &Bounds, aabb: &Rectangle, found: &mut Vec<usize>) {
+        // the root also owns everything that fell outside of it
+        if self.depth > 0 && !self.loose_rectangle().intersects(aabb) {
+            return;
+        }
+        for object in &self.objects {
+            if object.0.intersects(bounds) {
+                found.push(object.1);
+            }
+        }
+        if let Some(children) = self.children.as_ref() {
+            for child in children.iter() {
+                child.query_node(bounds, aabb, found);
+            }
+        }
+    }
+
+    /// Broad phase: every pair of stored objects whose bounds overlap, each pair
+    /// reported once with the lower index first. Loose bounds of siblings
+    /// overlap, so every object is queried against the whole tree.
+    pub fn collisions(&self) -> Vec<(usize, usize)> {
+        let mut pairs = Vec::new();
+        self.collect_pairs(self, &mut pairs);
+        pairs
+    }
+
+    fn collect_pairs(&self, root: &LooseQuadtree, pairs: &mut Vec<(usize, usize)>) {
+        let mut found = Vec::new();
+        for object in &self.objects {
+            found.clear();
+            root.query_into(&object.0, &mut found);
+            for &other in &found {
+                if other > object.1 {
+                    pairs.push((object.1, other));
+                }
+            }
+        }
+        if let Some(children) = self.children.as_ref() {
+            for child in children.iter() {
+                child.collect_pairs(root, pairs);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::prelude::*;
+
+    fn random_bounds(random: &mut StdRng) -> Bounds {
+        // some way off the 0..100 root and some bigger than a child
+        let (x, y) = (
+            random.gen_range(-150.0, 250.0),
+            random.gen_range(-150.0, 250.0),
+        );
+        if random.gen::<bool>() {
+            Bounds::Circle(x, y, random.gen_range(0.5, 20.0))
+        } else {
+            Bounds::Rect(Rectangle {
+                x,
+                y,
+                width: random.gen_range(0.5, 40.0),
+                height: random.gen_range(0.5, 40.0),
+            })
+        }
+    }
+
+    fn loose_tree() -> LooseQuadtree {
+        LooseQuadtree::new(
+            2,
+            6,
+            Rectangle {
+                x: 0.0,
+                y: 0.0,
+                width: 100.0,
+                height: 100.0,
+            },
+        )
+    }
+
+    #[test]
+    fn objects_outside_the_root_are_still_found() {
+        let mut tree = loose_tree();
+        for index in 0..8 {
+            tree.insert(Bounds::Circle(10.0 * index as f32, 10.0, 1.0), index);
+        }
+        tree.insert(Bounds::Circle(-200.0, 50.0, 1.0), 8);
+        tree.insert(Bounds::Circle(-200.5, 50.0, 1.0), 9);
+        assert_eq!(tree.query(&Bounds::Circle(-200.0, 50.0, 2.0)), vec![8, 9]);
+        assert_eq!(tree.collisions(), vec![(8, 9)]);
+    }
+
+    #[test]
+    fn loose_quadtree_matches_brute_force() {
+        let mut random = StdRng::seed_from_u64(26);
+        let mut tree = loose_tree();
+        let mut objects: Vec<Option<Bounds>> =
+            (0..300).map(|_| Some(random_bounds(&mut random))).collect();
+        for (index, bounds) in objects.iter().enumerate() {
+            tree.insert(bounds.unwrap(), index);
+        }
+        for index in (0..300).step_by(3) {
+            assert!(tree.remove(index));
+            objects[index] = None;
+        }
+        assert!(!tree.remove(0));
+        assert_eq!(tree.len(), 200);
+
+        for _ in 0..100 {
+            let query = random_bounds(&mut random);
+            let mut found = tree.query(&query);
+            found.sort_unstable();
+            let expected: Vec<usize> = (0..objects.len())
+                .filter(
+                    |&index| matches!(objects[index], Some(bounds) if bounds.intersects(&query)),
+                )
+                .collect();
+            assert_eq!(found, expected);
+        }
+
+        let mut pairs = tree.collisions();
+        pairs.sort_unstable();
+        let mut expected = Vec::new();
+        for a in 0..objects.len() {
+            for b in a + 1..objects.len() {
+                if let (Some(first), Some(second)) = (objects[a], objects[b]) {
+                    if first.intersects(&second) {
+                        expected.push((a, b));
+                    }
+                }
+            }
+        }
+        assert_eq!(pairs, expected);
+    }
+
+    #[test]
+    fn nearest_matches_sorted_brute_force() {
+        let mut random = StdRng::seed_from_u64(39);
+        let root = Rectangle {
+            x: 0.0,
+            y: 0.0,
+            width: 100.0,
+            height: 100.0,
+        };
+        for &count in &[0, 1, 7, 150] {
+            let mut points = Vec::new();
+            let mut tree = Quadtree::new(2, root);
+            for index in 0..count {
+                // every fourth point lands on top of an earlier one
+                let position = if index > 0 && index % 4 == 0 {
+                    points[random.gen_range(0, points.len())]
+                } else {
+                    cgmath::Vector2::new(random.gen_range(0.0, 100.0), random.gen_range(0.0, 100.0))
+                };
+                points.push(position);
+                assert!(tree.insert(position, index));
+            }
+
+            for &period in &[None, Some((100.0, 100.0))] {
+                for _ in 0..50 {
+                    let point = (random.gen_range(0.0, 100.0), random.gen_range(0.0, 100.0));
+                    let mut expected: Vec<(f32, usize)> = points
+                        .iter()
+                        .enumerate()
+                        .map(|(index, position)| {
+                            (
+                                point_distance2(point, (position.x, position.y), period),
+                                index,
+                            )
+                        })
+                        .collect();
+                    expected.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+                    for &k in &[1, 3, count, count + 5] {
+                        let mut found = Vec::new();
+                        tree.nearest_into(point, k, period, &mut found);
+                        assert_eq!(found, expected[..k.min(count)]);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,50 @@
+use cgmath::{Deg, Matrix4, Point3, Vector3};
+
+/// Perspective camera orbiting a target, angles in radians.
+#[derive(Debug, Copy, Clone)]
+pub struct Camera {
+    pub target: Point3<f32>,
+    pub distance: f32,
+    /// Rotation around the vertical axis.
+    pub yaw: f32,
+    /// Angle above the horizon, kept short of straight up or down.
+    pub pitch: f32,
+    pub fov: f32,
+    pub aspect: f32,
+    pub near: f32,
+    pub far: f32,
+}
+
+impl Camera {
+    pub fn new(target: Point3<f32>, distance: f32, aspect: f32) -> Self {
+        Self {
+            target,
+            distance,
+            yaw: 0.0,
+            pitch: 0.3,
+            fov: 60.0,
+            aspect,
+            near: 1.0,
+            far: distance * 4.0,
+        }
+    }
+
+    pub fn eye(&self) -> Point3<f32> {
+        let (yaw_sin, yaw_cos) = self.yaw.sin_cos();
+        let (pitch_sin, pitch_cos) = self.pitch.sin_cos();
+        self.target
+            + Vector3::new(pitch_cos * yaw_sin, pitch_sin, pitch_cos * yaw_cos) * self.distance
+    }
+
+    pub fn orbit(&mut self, yaw: f32, pitch: f32) {
+        let limit = std::f32::consts::FRAC_PI_2 - 0.01;
+        self.yaw += yaw;
+        self.pitch = (self.pitch + pitch).max(-limit).min(limit);
+    }
+
+    pub fn view_projection(&self) -> Matrix4<f32> {
+        let projection = cgmath::perspective(Deg(self.fov), self.aspect, self.near, self.far);
+        let view = Matrix4::look_at(self.eye(), self.target, Vector3::unit_y());
+        projection * view
+    }
+}
@@ -6,3 +6,9 @@ pub struct Instance {
     pub angle: f32,
     pub color: [f32; 4],
 }
+
+/// A mesh placed in the world by `model`, drawn with a perspective camera.
+pub struct Instance3D {
+    pub model: cgmath::Matrix4<f32>,
+    pub color: [f32; 4],
+}
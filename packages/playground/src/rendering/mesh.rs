@@ -0,0 +1,105 @@
+use crate::common_funcs as cf;
+use crate::rendering::{Camera, Instance3D};
+use js_sys::{Float32Array, Uint16Array, WebAssembly};
+use wasm_bindgen::JsCast;
+use web_sys::{WebGlBuffer, WebGlProgram, WebGlRenderingContext as GL, WebGlUniformLocation};
+
+/// Indexed triangle mesh drawn with depth testing, one draw per instance.
+pub struct Mesh {
+    indices: WebGlBuffer,
+    index_count: i32,
+    program: WebGlProgram,
+    u_color: WebGlUniformLocation,
+    u_transform: WebGlUniformLocation,
+    u_far: WebGlUniformLocation,
+    vertex_buffer: WebGlBuffer,
+}
+
+impl Mesh {
+    /// `vertices` are x, y, z triples and `indices` triangles into them.
+    pub fn new(gl: &GL, vertices: &[f32], indices: &[u16]) -> Self {
+        let program = cf::link_program(
+            gl,
+            crate::shaders::vert::color_3d::SHADER,
+            crate::shaders::frag::color_3d::SHADER,
+        )
+        .unwrap();
+
+        let memory_buffer = wasm_bindgen::memory()
+            .dyn_into::<WebAssembly::Memory>()
+            .unwrap()
+            .buffer();
+
+        let vertices_location = vertices.as_ptr() as u32 / 4;
+        let vertex_array = Float32Array::new(&memory_buffer)
+            .subarray(vertices_location, vertices_location + vertices.len() as u32);
+        let vertex_buffer = gl
+            .create_buffer()
+            .ok_or("failed to create vertex buffer")
+            .unwrap();
+        gl.bind_buffer(GL::ARRAY_BUFFER, Some(&vertex_buffer));
+        gl.buffer_data_with_array_buffer_view(GL::ARRAY_BUFFER, &vertex_array, GL::STATIC_DRAW);
+
+        let indices_location = indices.as_ptr() as u32 / 2;
+        let index_array = Uint16Array::new(&memory_buffer)
+            .subarray(indices_location, indices_location + indices.len() as u32);
+        let index_buffer = gl
+            .create_buffer()
+            .ok_or("failed to create index buffer")
+            .unwrap();
+        gl.bind_buffer(GL::ELEMENT_ARRAY_BUFFER, Some(&index_buffer));
+        gl.buffer_data_with_array_buffer_view(
+            GL::ELEMENT_ARRAY_BUFFER,
+            &index_array,
+            GL::STATIC_DRAW,
+        );
+
+        let u_color = gl.get_uniform_location(&program, "uColor").unwrap();
+        let u_transform = gl.get_uniform_location(&program, "uTransform").unwrap();
+        let u_far = gl.get_uniform_location(&program, "uFar").unwrap();
+
+        Self {
+            indices: index_buffer,
+            index_count: indices.len() as i32,
+            program,
+            u_color,
+            u_transform,
+            u_far,
+            vertex_buffer,
+        }
+    }
+
+    pub fn bind(&self, gl: &GL) {
+        gl.use_program(Some(&self.program));
+
+        gl.bind_buffer(GL::ARRAY_BUFFER, Some(&self.vertex_buffer));
+        gl.bind_buffer(GL::ELEMENT_ARRAY_BUFFER, Some(&self.indices));
+
+        gl.vertex_attrib_pointer_with_i32(0, 3, GL::FLOAT, false, 0, 0);
+        gl.enable_vertex_attrib_array(0);
+    }
+
+    pub fn render_instances(&self, gl: &GL, camera: &Camera, instances: Vec<Instance3D>) {
+        self.bind(gl);
+        gl.enable(GL::DEPTH_TEST);
+        gl.uniform1f(Some(&self.u_far), camera.far);
+        let view_projection = camera.view_projection();
+        for instance in instances {
+            gl.uniform4f(
+                Some(&self.u_color),
+                instance.color[0],
+                instance.color[1],
+                instance.color[2],
+                instance.color[3],
+            );
+            let transform: [[f32; 4]; 4] = (view_projection * instance.model).into();
+            gl.uniform_matrix4fv_with_f32_array(
+                Some(&self.u_transform),
+                false,
+                &transform.concat(),
+            );
+            gl.draw_elements_with_i32(GL::TRIANGLES, self.index_count, GL::UNSIGNED_SHORT, 0);
+        }
+        gl.disable(GL::DEPTH_TEST);
+    }
+}
@@ -1,7 +1,12 @@
+pub use camera::Camera;
 pub use instance::Instance;
+pub use instance::Instance3D;
+pub use mesh::Mesh;
 pub use rectangle::Rectangle;
 pub use triangle::Triangle;
 
+mod camera;
 mod instance;
+mod mesh;
 mod rectangle;
 mod triangle;
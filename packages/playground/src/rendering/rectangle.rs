@@ -6,8 +6,15 @@ use web_sys::{WebGlBuffer, WebGlProgram, WebGlRenderingContext as GL, WebGlUnifo
 
 const INDICES: [u16; 6] = [0, 1, 2, 2, 3, 0];
 const VERTICES: [f32; 12] = [0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 1.0, 1.0, 0.0, 0.0, 1.0, 0.0];
+// floats per vertex in a batch, position then color
+const BATCH_STRIDE: usize = 6;
 
 pub struct Rectangle {
+    // draws many rectangles at once from vertices positioned on the cpu
+    batch_program: WebGlProgram,
+    batch_vertices: WebGlBuffer,
+    batch_position: u32,
+    batch_color: u32,
     indices: WebGlBuffer,
     program: WebGlProgram,
     u_color: WebGlUniformLocation,
@@ -60,7 +67,20 @@ impl Rectangle {
         let u_translation = gl.get_uniform_location(&program, "u_Translation").unwrap();
         let u_rotation = gl.get_uniform_location(&program, "u_Rotation").unwrap();
 
+        let batch_program =
+            cf::link_program(gl, crate::shaders::batch::VERT, crate::shaders::batch::FRAG).unwrap();
+        let batch_vertices = gl
+            .create_buffer()
+            .ok_or("failed to create batch buffer")
+            .unwrap();
+        let batch_position = gl.get_attrib_location(&batch_program, "position") as u32;
+        let batch_color = gl.get_attrib_location(&batch_program, "color") as u32;
+
         Self {
+            batch_program,
+            batch_vertices,
+            batch_position,
+            batch_color,
             indices,
             program,
             u_color,
@@ -90,26 +110,54 @@ impl Rectangle {
         gl.enable_vertex_attrib_array(0);
     }
 
+    /// Draws every instance in a single draw call.
     pub fn render_instances(&self, gl: &GL, instances: Vec<Instance>) {
-        self.bind(gl);
-        for instance in instances {
-            gl.uniform4f(
-                Some(&self.u_color),
-                instance.color[0],
-                instance.color[1],
-                instance.color[2],
-                instance.color[3],
-            );
-            gl.uniform4f(
-                Some(&self.u_scale),
-                instance.width,
-                instance.height,
-                1.0,
-                1.0,
-            );
-            gl.uniform4f(Some(&self.u_translation), instance.x, instance.y, 0.0, 0.0);
-            gl.uniform1f(Some(&self.u_rotation), instance.angle);
-            gl.draw_elements_with_i32(GL::TRIANGLES, INDICES.len() as i32, GL::UNSIGNED_SHORT, 0);
+        if instances.is_empty() {
+            return;
         }
+
+        let mut vertices = Vec::with_capacity(instances.len() * 6 * BATCH_STRIDE);
+        for instance in &instances {
+            // the same scale, rotate then translate as the simple shader
+            let (sin, cos) = instance.angle.sin_cos();
+            let corner = |x: f32, y: f32| {
+                let (x, y) = (x * instance.width, y * instance.height);
+                (
+                    cos * x - sin * y + instance.x,
+                    sin * x + cos * y + instance.y,
+                )
+            };
+            let corners = [
+                corner(0.0, 0.0),
+                corner(1.0, 0.0),
+                corner(1.0, 1.0),
+                corner(0.0, 1.0),
+            ];
+            for &index in INDICES.iter() {
+                let (x, y) = corners[index as usize];
+                vertices.extend_from_slice(&[x, y]);
+                vertices.extend_from_slice(&instance.color);
+            }
+        }
+
+        gl.use_program(Some(&self.batch_program));
+        gl.bind_buffer(GL::ARRAY_BUFFER, Some(&self.batch_vertices));
+        gl.buffer_data_with_array_buffer_view(
+            GL::ARRAY_BUFFER,
+            &Float32Array::from(&vertices[..]),
+            GL::STREAM_DRAW,
+        );
+
+        let stride = (BATCH_STRIDE * 4) as i32;
+        gl.vertex_attrib_pointer_with_i32(self.batch_position, 2, GL::FLOAT, false, stride, 0);
+        gl.enable_vertex_attrib_array(self.batch_position);
+        gl.vertex_attrib_pointer_with_i32(self.batch_color, 4, GL::FLOAT, false, stride, 8);
+        gl.enable_vertex_attrib_array(self.batch_color);
+
+        gl.draw_arrays(GL::TRIANGLES, 0, (vertices.len() / BATCH_STRIDE) as i32);
+
+        // the other renderers only enable the attribute they feed
+        gl.disable_vertex_attrib_array(self.batch_position);
+        gl.disable_vertex_attrib_array(self.batch_color);
     }
 }
@@ -0,0 +1,21 @@
+pub const VERT: &str = r#"
+attribute vec2 position;
+attribute vec4 color;
+
+varying vec4 v_Color;
+
+void main() {
+    v_Color = color;
+    gl_Position = vec4(position, 0.0, 1.0);
+}
+"#;
+
+pub const FRAG: &str = r#"
+precision mediump float;
+
+varying vec4 v_Color;
+
+void main() {
+    gl_FragColor = v_Color;
+}
+"#;
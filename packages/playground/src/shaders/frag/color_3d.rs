@@ -0,0 +1,11 @@
+pub const SHADER: &str = r#"
+    precision mediump float;
+
+    uniform vec4 uColor;
+
+    varying float vShade;
+
+    void main() {
+        gl_FragColor = vec4(uColor.rgb * vShade, uColor.a);
+    }
+"#;
@@ -1,3 +1,4 @@
 pub mod frag;
 pub mod vert;
 pub mod simple;
+pub mod batch;
@@ -0,0 +1,13 @@
+pub const SHADER: &str = r#"
+    attribute vec4 aPosition;
+    uniform mat4 uTransform;
+    uniform float uFar;
+
+    varying float vShade;
+
+    void main() {
+        gl_Position = uTransform * aPosition;
+        // darken with distance so depth reads without lighting
+        vShade = 1.0 - clamp(gl_Position.w / uFar, 0.0, 0.7);
+    }
+"#;
@@ -1 +1,2 @@
 pub mod color_2d;
+pub mod color_3d;
@@ -0,0 +1,93 @@
+use cgmath::Vector2;
+
+use crate::simulations::Interaction;
+
+/// Everything about a boid that changes from one step to the next.
+#[derive(Debug, Copy, Clone)]
+pub struct BoidState {
+    pub position: Vector2<f32>,
+    pub velocity: Vector2<f32>,
+    pub force: Vector2<f32>,
+    pub wander_angle: f32,
+}
+
+impl BoidState {
+    pub fn new(position: Vector2<f32>, velocity: Vector2<f32>) -> Self {
+        Self {
+            position,
+            velocity,
+            force: Vector2::new(0.0, 0.0),
+            wander_angle: 0.0,
+        }
+    }
+}
+
+/// Boid states laid out as one array per field. The flock keeps two of these,
+/// reads the previous step from one and writes the next step into the other,
+/// then swaps them, so a step never copies or allocates per boid.
+#[derive(Debug, Clone, Default)]
+pub struct BoidBuffer {
+    pub positions: Vec<Vector2<f32>>,
+    pub velocities: Vec<Vector2<f32>>,
+    pub forces: Vec<Vector2<f32>>,
+    pub wander_angles: Vec<f32>,
+}
+
+impl BoidBuffer {
+    pub fn len(&self) -> usize {
+        self.positions.len()
+    }
+
+    pub fn get(&self, index: usize) -> BoidState {
+        BoidState {
+            position: self.positions[index],
+            velocity: self.velocities[index],
+            force: self.forces[index],
+            wander_angle: self.wander_angles[index],
+        }
+    }
+
+    pub fn set(&mut self, index: usize, state: BoidState) {
+        self.positions[index] = state.position;
+        self.velocities[index] = state.velocity;
+        self.forces[index] = state.force;
+        self.wander_angles[index] = state.wander_angle;
+    }
+
+    pub fn push(&mut self, state: BoidState) {
+        self.positions.push(state.position);
+        self.velocities.push(state.velocity);
+        self.forces.push(state.force);
+        self.wander_angles.push(state.wander_angle);
+    }
+
+    pub fn truncate(&mut self, len: usize) {
+        self.positions.truncate(len);
+        self.velocities.truncate(len);
+        self.forces.truncate(len);
+        self.wander_angles.truncate(len);
+    }
+
+    /// Keeps the boids whose entry in `keep` is true, preserving their order.
+    pub fn retain(&mut self, keep: &[bool]) {
+        let mut kept = 0;
+        for (index, _) in keep.iter().enumerate().filter(|&(_, &keep)| keep) {
+            let state = self.get(index);
+            self.set(kept, state);
+            kept += 1;
+        }
+        self.truncate(kept);
+    }
+}
+
+/// A boid seen by another during a step. `offset` points from the observer to
+/// the neighbour, already taking the short way around a wrapping world. The
+/// 3D flock uses the same type with `Vector3`s.
+#[derive(Debug, Copy, Clone)]
+pub struct Neighbour<V = Vector2<f32>> {
+    pub offset: V,
+    pub velocity: V,
+    pub distance: f32,
+    /// How the observer's species reacts to the neighbour's.
+    pub interaction: Interaction,
+}
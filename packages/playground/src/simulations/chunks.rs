@@ -0,0 +1,157 @@
+use std::cell::Cell;
+use wasm_bindgen::prelude::*;
+
+/// Cells from `x0, y0` up to but not including `x1, y1`.
+pub type Region = (u32, u32, u32, u32);
+
+/// A grid world split into square chunks that fall asleep once nothing in
+/// them changes. Anything that changes a cell wakes the chunks around it for
+/// the next step, so work only happens where something is going on.
+pub struct Chunks {
+    dimensions: (u32, u32),
+    size: u32,
+    columns: u32,
+    rows: u32,
+    awake: Vec<bool>,
+    // woken during this step, awake for the next one
+    next: Vec<Cell<bool>>,
+    // cells changed in each chunk during the last step
+    activity: Vec<Cell<u32>>,
+}
+
+impl Chunks {
+    pub fn new(width: u32, height: u32, size: u32) -> Self {
+        // a partial chunk covers what's left at the far edges
+        let columns = width.div_ceil(size);
+        let rows = height.div_ceil(size);
+        let count = (columns * rows) as usize;
+        Self {
+            dimensions: (width, height),
+            size,
+            columns,
+            rows,
+            awake: vec![true; count],
+            next: vec![Cell::new(true); count],
+            activity: vec![Cell::new(0); count],
+        }
+    }
+
+    fn chunk(&self, x: i32, y: i32) -> Option<usize> {
+        if x < 0 || y < 0 || x >= self.dimensions.0 as i32 || y >= self.dimensions.1 as i32 {
+            return None;
+        }
+        let (column, row) = (x as u32 / self.size, y as u32 / self.size);
+        Some((row * self.columns + column) as usize)
+    }
+
+    pub fn is_awake(&self, x: i32, y: i32) -> bool {
+        matches!(self.chunk(x, y), Some(chunk) if self.awake[chunk])
+    }
+
+    /// Chunks across the world, counting a partial one at the right edge.
+    pub fn columns(&self) -> u32 {
+        self.columns
+    }
+
+    /// Keeps the chunks around a cell awake for the next step, since its
+    /// neighbours may be able to move now.
+    pub fn wake(&self, x: i32, y: i32) {
+        for dy in -1..=1 {
+            for dx in -1..=1 {
+                if let Some(chunk) = self.chunk(x + dx, y + dy) {
+                    self.next[chunk].set(true);
+                }
+            }
+        }
+    }
+
+    /// Counts a change to a cell in its chunk's activity and wakes the chunks
+    /// around it.
+    pub fn record(&self, x: i32, y: i32) {
+        if let Some(chunk) = self.chunk(x, y) {
+            self.activity[chunk].set(self.activity[chunk].get() + 1);
+        }
+        self.wake(x, y);
+    }
+
+    /// Keeps a chunk and the eight around it awake for the next step.
+    pub fn wake_around(&self, chunk: usize) {
+        let (column, row) = (chunk as u32 % self.columns, chunk as u32 / self.columns);
+        for other_row in row.saturating_sub(1)..(row + 2).min(self.rows) {
+            for other_column in column.saturating_sub(1)..(column + 2).min(self.columns) {
+                self.next[(other_row * self.columns + other_column) as usize].set(true);
+            }
+        }
+    }
+
+    /// Wakes every chunk for this step and the next.
+    pub fn wake_all(&mut self) {
+        for (awake, next) in self.awake.iter_mut().zip(&self.next) {
+            *awake = true;
+            next.set(true);
+        }
+    }
+
+    /// Makes the chunks woken during the last step the awake ones and starts
+    /// counting activity again.
+    pub fn begin_step(&mut self) {
+        for ((awake, next), activity) in self.awake.iter_mut().zip(&self.next).zip(&self.activity) {
+            *awake = next.replace(false);
+            activity.set(0);
+        }
+    }
+
+    /// Indexes of the awake chunks, row by row from the bottom left.
+    pub fn awake_chunks(&self) -> Vec<usize> {
+        (0..self.awake.len())
+            .filter(|&chunk| self.awake[chunk])
+            .collect()
+    }
+
+    /// The cells covered by a chunk, cut short at the edges of the world.
+    pub fn region(&self, chunk: usize) -> Region {
+        let (column, row) = (chunk as u32 % self.columns, chunk as u32 / self.columns);
+        let (x, y) = (column * self.size, row * self.size);
+        (
+            x,
+            y,
+            (x + self.size).min(self.dimensions.0),
+            (y + self.size).min(self.dimensions.1),
+        )
+    }
+
+    pub fn stats(&self, tiles: usize, particles: usize) -> SandStats {
+        SandStats {
+            tiles: tiles as u32,
+            particles: particles as u32,
+            columns: self.columns,
+            rows: self.rows,
+            awake: self.awake.iter().filter(|&&awake| awake).count() as u32,
+            activity: self.activity.iter().map(Cell::get).collect(),
+        }
+    }
+}
+
+/// How busy a falling sand world was during its last step.
+#[wasm_bindgen]
+#[derive(Debug, Clone, Default)]
+pub struct SandStats {
+    pub tiles: u32,
+    /// Tiles flying outside the grid.
+    pub particles: u32,
+    /// Chunks across and up the world.
+    pub columns: u32,
+    pub rows: u32,
+    /// Chunks that were stepped, the rest were asleep.
+    pub awake: u32,
+    activity: Vec<u32>,
+}
+
+#[wasm_bindgen]
+impl SandStats {
+    /// Cells changed in each chunk, row by row from the bottom left.
+    #[wasm_bindgen(getter)]
+    pub fn activity(&self) -> Vec<u32> {
+        self.activity.clone()
+    }
+}
@@ -0,0 +1,232 @@
+use rand::prelude::*;
+use serde::Deserialize;
+
+/// The cells an emitter pours from or a sink drains, in sand world cells
+/// from the bottom left.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Shape {
+    /// Just the cell the emitter or sink is at.
+    #[default]
+    Point,
+    /// Cells within `radius` of the emitter or sink.
+    Circle { radius: u32 },
+    /// Cells up and to the right of the emitter or sink, which is the bottom
+    /// left corner.
+    Rectangle { width: u32, height: u32 },
+}
+
+impl Shape {
+    /// Every cell in the shape placed at `x, y` that's inside a world of
+    /// `width` by `height` cells.
+    pub fn cells(&self, x: i32, y: i32, width: u32, height: u32) -> Vec<(i32, i32)> {
+        // the shape's bounding box, clipped to the world
+        let (x, y) = (x as i64, y as i64);
+        let (left, bottom, right, top) = match *self {
+            Shape::Point => (x, y, x + 1, y + 1),
+            Shape::Circle { radius } => {
+                let radius = radius as i64;
+                (x - radius, y - radius, x + radius + 1, y + radius + 1)
+            }
+            Shape::Rectangle {
+                width: shape_width,
+                height: shape_height,
+            } => (x, y, x + shape_width as i64, y + shape_height as i64),
+        };
+        let (left, right) = (left.max(0), right.min(width as i64));
+        let (bottom, top) = (bottom.max(0), top.min(height as i64));
+        let mut cells = Vec::new();
+        for cell_y in bottom..top {
+            for cell_x in left..right {
+                let inside = match *self {
+                    // wide enough not to overflow for any radius
+                    Shape::Circle { radius } => {
+                        ((cell_x - x) as i128).pow(2) + ((cell_y - y) as i128).pow(2)
+                            <= (radius as i128).pow(2)
+                    }
+                    _ => true,
+                };
+                if inside {
+                    cells.push((cell_x as i32, cell_y as i32));
+                }
+            }
+        }
+        cells
+    }
+
+    // a random cell in the shape placed at `x, y`
+    fn pick(&self, random: &mut StdRng, x: i32, y: i32) -> (i32, i32) {
+        match *self {
+            Shape::Point => (x, y),
+            Shape::Circle { radius } => {
+                let radius = radius as i32;
+                loop {
+                    let (dx, dy) = (
+                        random.gen_range(-radius, radius + 1),
+                        random.gen_range(-radius, radius + 1),
+                    );
+                    if dx * dx + dy * dy <= radius * radius {
+                        return (x + dx, y + dy);
+                    }
+                }
+            }
+            Shape::Rectangle { width, height } => (
+                x + random.gen_range(0, width.max(1) as i32),
+                y + random.gen_range(0, height.max(1) as i32),
+            ),
+        }
+    }
+}
+
+/// Pours tiles of a material into the sand world. A tile that would land on
+/// a taken cell isn't placed.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Emitter {
+    /// Name of the material poured.
+    pub material: String,
+    pub x: i32,
+    pub y: i32,
+    #[serde(default)]
+    pub shape: Shape,
+    /// Tiles poured each step, fractions carry over to the next. At most
+    /// `Emitter::MAX_RATE`.
+    #[serde(default = "Emitter::default_rate")]
+    pub rate: f32,
+    /// How many cells each tile may land away from the shape.
+    #[serde(default)]
+    pub spread: u32,
+    /// Steps the emitter pours for, 0 for forever.
+    #[serde(default)]
+    pub lifetime: u32,
+    #[serde(skip)]
+    owed: f32,
+    #[serde(skip)]
+    age: u32,
+}
+
+/// Deletes every tile that reaches its cells.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Sink {
+    pub x: i32,
+    pub y: i32,
+    #[serde(default)]
+    pub shape: Shape,
+    /// Steps the sink drains for, 0 for forever.
+    #[serde(default)]
+    pub lifetime: u32,
+    #[serde(skip)]
+    age: u32,
+}
+
+/// Emitters and sinks to set a sand world up with, as written in a scene
+/// file like `{"emitters": [{"material": "water", "x": 10, "y": 90}],
+/// "sinks": [{"x": 50, "y": 0, "shape": {"type": "circle", "radius": 3}}]}`.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Scene {
+    #[serde(default)]
+    pub emitters: Vec<Emitter>,
+    #[serde(default)]
+    pub sinks: Vec<Sink>,
+}
+
+impl Emitter {
+    /// Most tiles an emitter pours in a step, however high its rate.
+    pub const MAX_RATE: f32 = 1000.0;
+
+    pub fn new(material: &str, x: i32, y: i32) -> Self {
+        Self {
+            material: material.to_string(),
+            x,
+            y,
+            shape: Shape::Point,
+            rate: Emitter::default_rate(),
+            spread: 0,
+            lifetime: 0,
+            owed: 0.0,
+            age: 0,
+        }
+    }
+
+    pub fn with_shape(mut self, shape: Shape) -> Self {
+        self.shape = shape;
+        self
+    }
+
+    pub fn with_rate(mut self, rate: f32, spread: u32) -> Self {
+        self.rate = rate;
+        self.spread = spread;
+        self
+    }
+
+    pub fn with_lifetime(mut self, lifetime: u32) -> Self {
+        self.lifetime = lifetime;
+        self
+    }
+
+    /// The cells to pour a tile into this step.
+    pub fn emit(&mut self, random: &mut StdRng) -> Vec<(i32, i32)> {
+        self.age += 1;
+        self.owed += self.rate.clamp(0.0, Emitter::MAX_RATE);
+        let count = self.owed.floor();
+        self.owed -= count;
+        let spread = self.spread as i32;
+        (0..count as u32)
+            .map(|_| {
+                let (x, y) = self.shape.pick(random, self.x, self.y);
+                (
+                    x + random.gen_range(-spread, spread + 1),
+                    y + random.gen_range(-spread, spread + 1),
+                )
+            })
+            .collect()
+    }
+
+    /// Whether the emitter has poured for its whole lifetime.
+    pub fn is_spent(&self) -> bool {
+        self.lifetime > 0 && self.age >= self.lifetime
+    }
+
+    fn default_rate() -> f32 {
+        1.0
+    }
+}
+
+impl Sink {
+    /// The cells of a world of `width` by `height` cells to drain this step.
+    pub fn drain(&mut self, width: u32, height: u32) -> Vec<(i32, i32)> {
+        self.age += 1;
+        self.shape.cells(self.x, self.y, width, height)
+    }
+
+    /// Whether the sink has drained for its whole lifetime.
+    pub fn is_spent(&self) -> bool {
+        self.lifetime > 0 && self.age >= self.lifetime
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shapes_are_clipped_to_the_world() {
+        let huge = Shape::Circle { radius: u32::MAX };
+        assert_eq!(huge.cells(5, 5, 4, 3).len(), 12);
+        let rectangle = Shape::Rectangle {
+            width: 10,
+            height: 2,
+        };
+        assert_eq!(
+            rectangle.cells(-3, 1, 4, 2),
+            vec![(0, 1), (1, 1), (2, 1), (3, 1)]
+        );
+        assert!(Shape::Point.cells(4, 0, 4, 3).is_empty());
+    }
+
+    #[test]
+    fn emitters_pour_at_most_the_max_rate() {
+        let mut random = StdRng::seed_from_u64(0);
+        let mut emitter = Emitter::new("sand", 0, 0).with_rate(f32::MAX, 0);
+        assert_eq!(emitter.emit(&mut random).len(), Emitter::MAX_RATE as usize);
+    }
+}
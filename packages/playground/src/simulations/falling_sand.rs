@@ -1,22 +1,54 @@
-use std::cell::Cell;
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
 use web_sys::WebGlRenderingContext as GL;
 use rand::prelude::*;
+use cgmath::prelude::*;
+use cgmath::Vector2;
 
 use crate::{
     rendering::{Rectangle, Instance},
-    simulations::Simulation,
+    simulations::{Chunks, Emitter, Flags, HeatField, MaterialTable, Phase, SandStats, Scene, Shape, Simulation, Sink},
     utils::{CoordinateEncoder, FlatEncoder},
 };
 
-const X_FLAG: u32    = 0xFFF00000;
-const Y_FLAG: u32    = 0x000FFF00;
-const TILE_FLAG: u32 = 0x000000FF;
+// width and height in cells of the chunks that sleep once settled
+const CHUNK_SIZE: u32 = 64;
+// the eight neighbouring cells anticlockwise from the right, so turning is
+// stepping around the ring
+const DIRECTIONS: [(i32, i32); 8] = [(1, 0), (1, 1), (0, 1), (-1, 1), (-1, 0), (-1, -1), (0, -1), (1, -1)];
+// impact speed in cells a step above which a landing tile splashes sideways
+const SPLASH_SPEED: f32 = 2.0;
+// sideways speed in cells a step below which a tile settles
+const SETTLE_SPEED: f32 = 0.5;
+// how far sideways a liquid looks for somewhere lower to flow to
+const FLOW_REACH: i32 = 16;
+// most cells of a body of liquid searched for somewhere lower to push a tile
+const PRESSURE_REACH: usize = 1024;
 
 #[derive(Clone, Copy)]
 struct Tile {
     x: u32,
     y: u32,
+    // index into the world's material table
     id: u8,
+    // steps left before the tile decays, unused for materials that last
+    life: u16,
+    // the world step this tile last moved in, so it moves at most once a step
+    stepped: u32,
+    // cells a step
+    velocity: Vector2<f32>,
+}
+
+// A tile flung out of the grid, flying freely until it lands back in it. It
+// doesn't age, react or pass on heat while it's in the air.
+#[derive(Clone, Copy)]
+struct Particle {
+    // in cells, a cell covering from its coordinates to the next ones up
+    position: Vector2<f32>,
+    velocity: Vector2<f32>,
+    id: u8,
+    life: u16,
+    temperature: f32,
 }
 
 struct TileStorage {
@@ -50,9 +82,17 @@ impl TileStorage {
         result
     }
 
-    pub fn insert(&mut self, tile: Tile) {
-        self.tilemap[self.encoder.encode(tile.x as i32, tile.y as i32).unwrap()].replace(Some(self.tiles.len()));
-        self.tiles.push(Cell::<Tile>::new(tile));
+    /// Adds a tile unless its cell is taken or outside the world. Returns
+    /// whether it was added.
+    pub fn insert(&mut self, tile: Tile) -> bool {
+        match self.encoder.encode(tile.x as i32, tile.y as i32) {
+            Some(map_index) if self.tilemap[map_index].get().is_none() => {
+                self.tilemap[map_index].set(Some(self.tiles.len()));
+                self.tiles.push(Cell::<Tile>::new(tile));
+                true
+            }
+            _ => false,
+        }
     }
 
     pub fn swap(&self, tile: &Cell<Tile>, old_coords: (u32, u32), new_coords: (i32, i32)) {
@@ -60,121 +100,1018 @@ impl TileStorage {
         // Only swap if new location is valid.
         if let Some(new_index) = self.encoder.encode(new_coords.0, new_coords.1) {
             self.tilemap[old_index].swap(&self.tilemap[new_index]);
-            tile.set(Tile { x: new_coords.0 as u32, y: new_coords.1 as u32, id: tile.get().id });
+            tile.set(Tile { x: new_coords.0 as u32, y: new_coords.1 as u32, ..tile.get() });
             // Update the coordinates of the swapped tile if its an actual tile.
             if let Some(old_tile) = self.get(old_coords.0 as i32, old_coords.1 as i32) {
-                old_tile.set(Tile { x: old_coords.0, y: old_coords.1, id: old_tile.get().id });
+                old_tile.set(Tile { x: old_coords.0, y: old_coords.1, ..old_tile.get() });
             }
         }
     }
+
+    /// Removes the tile at `index` in `tiles`, moving the last tile into its
+    /// place.
+    pub fn remove(&mut self, index: usize) {
+        let tile = self.tiles[index].get();
+        self.tilemap[self.encoder.encode(tile.x as i32, tile.y as i32).unwrap()].set(None);
+        self.tiles.swap_remove(index);
+        if let Some(moved) = self.tiles.get(index) {
+            let moved = moved.get();
+            self.tilemap[self.encoder.encode(moved.x as i32, moved.y as i32).unwrap()].set(Some(index));
+        }
+    }
+
+    /// Removes the tile in a cell, if there is one. Returns whether there was.
+    pub fn remove_at(&mut self, x: i32, y: i32) -> bool {
+        match self.get_index(x, y) {
+            Some(index) => {
+                self.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Turns a tile into another material where it stands.
+    pub fn transform(&self, tile: &Cell<Tile>, id: u8, life: u16) {
+        tile.set(Tile { id, life, ..tile.get() });
+    }
+
+    /// Removes every tile.
+    pub fn clear(&mut self) {
+        self.tiles.clear();
+        for entry in &self.tilemap {
+            entry.set(None);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.tiles.len()
+    }
+
+    /// Checks that `tiles` and `tilemap` describe the same world: every tile
+    /// is in the cell it thinks it's in, and every cell entry points at the
+    /// tile in that cell.
+    pub fn check_invariants(&self) -> Result<(), String> {
+        for (index, tile) in self.tiles.iter().enumerate() {
+            let tile = tile.get();
+            let map_index = self.encoder.encode(tile.x as i32, tile.y as i32)
+                .ok_or_else(|| format!("tile {} is outside the world at ({}, {})", index, tile.x, tile.y))?;
+            if self.tilemap[map_index].get() != Some(index) {
+                return Err(format!("tile {} at ({}, {}) is mapped to {:?}", index, tile.x, tile.y, self.tilemap[map_index].get()));
+            }
+        }
+        let mapped = self.tilemap.iter().filter(|entry| entry.get().is_some()).count();
+        if mapped != self.tiles.len() {
+            return Err(format!("{} cells are mapped for {} tiles", mapped, self.tiles.len()));
+        }
+        Ok(())
+    }
 }
 
 pub struct FallingSand {
     dimensions: (u32, u32),
     tiles: TileStorage,
-    renderer: Rectangle,
-    random: ThreadRng,
-    spawn_count: u32,
+    materials: MaterialTable,
+    heat: HeatField,
+    chunks: Chunks,
+    // draw every cell tinted by its temperature
+    show_heat: bool,
+    // absent when running headless
+    renderer: Option<Rectangle>,
+    random: StdRng,
+    // pull on falling tiles in cells a step per step
+    gravity: Vector2<f32>,
+    // fastest a tile falls in cells a step
+    terminal_velocity: f32,
+    // push liquids through connected bodies so they level out
+    pressure: bool,
+    // steps taken so far, which also decides which way rows are scanned
+    steps: u32,
+    // each with its id and the tile id of the material it pours
+    emitters: Vec<(usize, Emitter, u8)>,
+    next_emitter_id: usize,
+    sinks: Vec<(usize, Sink)>,
+    next_sink_id: usize,
+    // tiles out of the grid
+    particles: Vec<Particle>,
+    // check the tile storage after every step, panicking if it's broken
+    debug: bool,
+    // scratch space for pushing liquids, kept between steps
+    bodies: RefCell<Bodies>,
+}
+
+// Everything a tile reads or changes during a step, borrowed from the world
+// field by field so the random number generator can be borrowed alongside.
+struct Step<'a> {
+    tiles: &'a TileStorage,
+    materials: &'a MaterialTable,
+    heat: &'a HeatField,
+    chunks: &'a Chunks,
+    gravity: Vector2<f32>,
+    terminal_velocity: f32,
+    pressure: bool,
+    // index into `DIRECTIONS` of the neighbour closest to straight down, none
+    // without gravity
+    down: Option<usize>,
+    bodies: &'a RefCell<Bodies>,
+}
+
+// The bodies of liquid found so far this step, so each is searched once
+// however many of its surface tiles push.
+struct Bodies {
+    // the step and the body each cell was last found in
+    labels: Vec<(u32, usize)>,
+    step: u32,
+    bodies: Vec<Body>,
+    // the empty cells touching each body, lowest first
+    outlets: Vec<(i32, i32)>,
+    queue: VecDeque<(i32, i32)>,
+}
+
+struct Body {
+    id: u8,
+    // the body's untaken outlets
+    next: usize,
+    end: usize,
+}
+
+impl Bodies {
+    fn new(width: u32, height: u32) -> Self {
+        Bodies {
+            labels: vec![(0, 0); (width * height) as usize],
+            step: 0,
+            bodies: Vec::new(),
+            outlets: Vec::new(),
+            queue: VecDeque::new(),
+        }
+    }
+
+    // Forgets the bodies found last step, without clearing every cell.
+    fn begin_step(&mut self) {
+        self.step = self.step.wrapping_add(1);
+        if self.step == 0 {
+            self.labels.iter_mut().for_each(|label| *label = (0, 0));
+            self.step = 1;
+        }
+        self.bodies.clear();
+        self.outlets.clear();
+    }
 }
 
 impl FallingSand {
     pub fn new(gl: &GL, width: u32, height: u32) -> Self {
-        let mut tiles = TileStorage::new(width, height);
-        let mut rng = rand::thread_rng();
+        let mut sand = FallingSand::headless(width, height, rand::thread_rng().gen());
         let encoder = FlatEncoder { dimensions: (width, height) };
 
         for index in 0..width*height {
-            let weight = rng.gen::<f32>();
+            let weight = sand.random.gen::<f32>();
             let tile_id = if weight > 0.9 {
-                1
+                MaterialTable::SAND
             } else {
-                0
+                MaterialTable::EMPTY
             };
 
-            if tile_id != 0 {
+            if tile_id != MaterialTable::EMPTY {
                 let (x, y) = encoder.decode(index as usize);
-                let tile = Tile { x, y, id: tile_id };
-                tiles.insert(tile);
+                sand.place(x as i32, y as i32, tile_id);
             }
         }
 
+        sand.renderer = Some(Rectangle::new(&gl));
+        // a stream of water along the top left for a while
+        let pour = Emitter::new("water", 4, height as i32 - 1)
+            .with_shape(Shape::Rectangle { width: 20, height: 1 })
+            .with_rate(5.0, 0)
+            .with_lifetime(1000);
+        sand.add_emitter(pour).unwrap();
+        sand
+    }
+
+    /// An empty world without any GL resources, for stepping outside the
+    /// browser. The same seed always gives the same steps.
+    pub fn headless(width: u32, height: u32, seed: u64) -> Self {
         Self {
             dimensions: (width, height),
-            tiles,
-            renderer: Rectangle::new(&gl),
-            random: rng,
-            spawn_count: 0,
+            tiles: TileStorage::new(width, height),
+            materials: MaterialTable::default(),
+            heat: HeatField::new(width, height, 20.0),
+            chunks: Chunks::new(width, height, CHUNK_SIZE),
+            show_heat: false,
+            renderer: None,
+            random: StdRng::seed_from_u64(seed),
+            gravity: Vector2::new(0.0, -0.1),
+            terminal_velocity: 8.0,
+            pressure: true,
+            steps: 0,
+            emitters: Vec::new(),
+            next_emitter_id: 0,
+            sinks: Vec::new(),
+            next_sink_id: 0,
+            particles: Vec::new(),
+            debug: cfg!(debug_assertions),
+            bodies: RefCell::new(Bodies::new(width, height)),
         }
     }
 }
 
-impl Simulation for FallingSand {
-    fn update(&mut self) {
-        if self.spawn_count < 1000 {
-            self.spawn_count += 1;
-            for point in 0..20 {
-                if self.random.gen::<f32>() > 0.75 {
-                    self.tiles.insert(Tile { x: point + 4, y: self.dimensions.1 - 1, id: 2 })
-                }
-            }
-        }
-
-        for raw_tile in self.tiles.tiles.iter() {
-            let tile = raw_tile.get();
-            if tile.y > 0 {
-                if tile.id == 1 {
-                    if let Some(below) = self.tiles.get(tile.x as i32, tile.y as i32 - 1) {
-                        if below.get().id == 2 {
-                            self.tiles.swap(raw_tile, (tile.x, tile.y), (tile.x as i32, tile.y as i32 - 1));
-                        } else {
-                            let direction = if self.random.gen::<f32>() > 0.5 { -1 } else { 1 };
-                            if let Some(below) = self.tiles.get(tile.x as i32 + direction, tile.y as i32 - 1) {
-                                if below.get().id == 2 {
-                                    self.tiles.swap(raw_tile, (tile.x, tile.y), (tile.x as i32 + direction, tile.y as i32 - 1));
-                                }
-                            } else {
-                                self.tiles.swap(raw_tile, (tile.x, tile.y), (tile.x as i32 + direction, tile.y as i32 - 1));
-                            }
-                        }
-                    } else {
-                        self.tiles.swap(raw_tile, (tile.x, tile.y), (tile.x as i32, tile.y as i32 - 1));
+impl FallingSand {
+    /// Sets which way and how hard tiles fall, in cells a step per step. The
+    /// cell rules follow whichever of the eight neighbours is closest to
+    /// straight down, so the world can be tipped over. Nothing falls without
+    /// gravity.
+    pub fn set_gravity(&mut self, x: f32, y: f32) {
+        self.gravity = Vector2::new(x, y);
+        self.chunks.wake_all();
+    }
+
+    /// Caps how fast tiles fall, in cells a step.
+    pub fn set_terminal_velocity(&mut self, speed: f32) {
+        self.terminal_velocity = speed.max(1.0);
+    }
+
+    /// Lets the weight of a body of liquid push its surface up elsewhere, so
+    /// basins joined under the surface level out like communicating vessels.
+    /// On by default.
+    pub fn set_pressure(&mut self, pressure: bool) {
+        self.pressure = pressure;
+        self.chunks.wake_all();
+    }
+
+    /// Adds a material described in JSON, or replaces the one with the same
+    /// name, and returns its tile id. See `MaterialTable::add_json`.
+    pub fn add_material_json(&mut self, json: &str) -> Result<u8, String> {
+        self.materials.add_json(json)
+    }
+
+    /// Removes every tile, flying or not, every emitter and sink, and cools
+    /// the world back down.
+    pub fn clear(&mut self) {
+        self.tiles.clear();
+        self.particles.clear();
+        self.heat.reset();
+        self.chunks.wake_all();
+        self.emitters.clear();
+        self.sinks.clear();
+    }
+
+    /// Adds an emitter and returns its id, or an error if it pours a
+    /// material that isn't in the table.
+    pub fn add_emitter(&mut self, emitter: Emitter) -> Result<usize, String> {
+        let material = self.materials.find(&emitter.material)
+            .ok_or_else(|| format!("unknown material `{}`", emitter.material))?;
+        let id = self.next_emitter_id;
+        self.next_emitter_id += 1;
+        self.emitters.push((id, emitter, material));
+        Ok(id)
+    }
+
+    /// Adds an emitter described in JSON like `{"material": "sand", "x": 40,
+    /// "y": 90, "shape": {"type": "circle", "radius": 2}, "rate": 0.5,
+    /// "spread": 1, "lifetime": 600}`, where all but the material and
+    /// position can be left out. Returns its id.
+    pub fn add_emitter_json(&mut self, json: &str) -> Result<usize, String> {
+        let emitter = serde_json::from_str(json).map_err(|error| error.to_string())?;
+        self.add_emitter(emitter)
+    }
+
+    pub fn remove_emitter(&mut self, id: usize) -> bool {
+        let count = self.emitters.len();
+        self.emitters.retain(|emitter| emitter.0 != id);
+        self.emitters.len() != count
+    }
+
+    /// Adds a sink and returns its id.
+    pub fn add_sink(&mut self, sink: Sink) -> usize {
+        let id = self.next_sink_id;
+        self.next_sink_id += 1;
+        self.sinks.push((id, sink));
+        id
+    }
+
+    /// Adds a sink described in JSON like `{"x": 60, "y": 0, "shape":
+    /// {"type": "rectangle", "width": 8, "height": 2}, "lifetime": 0}`.
+    /// Returns its id.
+    pub fn add_sink_json(&mut self, json: &str) -> Result<usize, String> {
+        let sink = serde_json::from_str(json).map_err(|error| error.to_string())?;
+        Ok(self.add_sink(sink))
+    }
+
+    pub fn remove_sink(&mut self, id: usize) -> bool {
+        let count = self.sinks.len();
+        self.sinks.retain(|sink| sink.0 != id);
+        self.sinks.len() != count
+    }
+
+    /// Swaps the emitters and sinks for the ones in a JSON scene, see
+    /// `Scene`. Nothing changes if any emitter pours an unknown material.
+    /// Ids carry on counting from before, emitters first and then sinks in
+    /// the order they're written.
+    pub fn load_scene_json(&mut self, json: &str) -> Result<(), String> {
+        let scene: Scene = serde_json::from_str(json).map_err(|error| error.to_string())?;
+        if let Some(emitter) = scene.emitters.iter().find(|emitter| self.materials.find(&emitter.material).is_none()) {
+            return Err(format!("unknown material `{}`", emitter.material));
+        }
+        self.emitters.clear();
+        self.sinks.clear();
+        for emitter in scene.emitters {
+            self.add_emitter(emitter)?;
+        }
+        for sink in scene.sinks {
+            self.add_sink(sink);
+        }
+        Ok(())
+    }
+
+    /// Checks the tile storage after every step and panics if it's broken.
+    /// On by default in debug builds.
+    pub fn set_debug(&mut self, debug: bool) {
+        self.debug = debug;
+    }
+
+    /// Tints every cell by its temperature, blue for cold and red through
+    /// white for hot.
+    pub fn show_heat(&mut self, show: bool) {
+        self.show_heat = show;
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.tiles.len()
+    }
+
+    /// Tiles flying outside the grid.
+    #[cfg(test)]
+    pub fn particle_count(&self) -> usize {
+        self.particles.len()
+    }
+
+    /// Tiles, flying tiles, awake chunks and how much changed in each chunk
+    /// last step.
+    pub fn stats(&self) -> SandStats {
+        self.chunks.stats(self.tiles.len(), self.particles.len())
+    }
+
+    /// Temperature of a cell in degrees.
+    #[cfg(test)]
+    pub fn temperature(&self, x: i32, y: i32) -> f32 {
+        self.heat.get(x, y)
+    }
+
+    /// The tile id of the material called `name`.
+    pub fn material_id(&self, name: &str) -> Option<u8> {
+        self.materials.find(name)
+    }
+
+    /// Adds a reaction between two materials described in JSON. See
+    /// `MaterialTable::add_reaction_json`.
+    pub fn add_reaction_json(&mut self, json: &str) -> Result<(), String> {
+        self.materials.add_reaction_json(json)
+    }
+
+    /// Fills the empty cells within `radius` of a cell with material `id`, or
+    /// clears them when `id` is `MaterialTable::EMPTY`. Cells count from the
+    /// bottom left.
+    pub fn paint(&mut self, x: i32, y: i32, radius: i32, id: u8) {
+        if id as usize >= self.materials.len() {
+            return;
+        }
+        for cell_y in y - radius..=y + radius {
+            for cell_x in x - radius..=x + radius {
+                if (cell_x - x).pow(2) + (cell_y - y).pow(2) > radius * radius {
+                    continue;
+                }
+                if id == MaterialTable::EMPTY {
+                    if self.tiles.remove_at(cell_x, cell_y) {
+                        self.chunks.record(cell_x, cell_y);
+                    }
+                } else {
+                    self.place(cell_x, cell_y, id);
+                }
+            }
+        }
+    }
+
+    /// Takes the tile in a cell out of the grid and sets it flying with
+    /// `velocity`, in cells a step, until it lands again. Returns whether
+    /// there was a tile.
+    pub fn eject(&mut self, x: i32, y: i32, velocity: Vector2<f32>) -> bool {
+        let tile = match self.tiles.get(x, y) {
+            Some(tile) => tile.get(),
+            None => return false,
+        };
+        self.tiles.remove_at(x, y);
+        self.particles.push(Particle {
+            position: Vector2::new(x as f32 + 0.5, y as f32 + 0.5),
+            velocity,
+            id: tile.id,
+            life: tile.life,
+            temperature: self.heat.get(x, y),
+        });
+        self.chunks.record(x, y);
+        true
+    }
+
+    /// Flings every tile that isn't solid within `radius` of a cell out of the
+    /// grid, away from the cell at up to `strength` cells a step, fastest
+    /// nearest the middle.
+    pub fn explode(&mut self, x: i32, y: i32, radius: i32, strength: f32) {
+        // straight up against gravity from the middle itself
+        let up = if self.gravity.magnitude2() > 0.0 { -self.gravity.normalize() } else { Vector2::unit_y() };
+        for cell_y in y - radius..=y + radius {
+            for cell_x in x - radius..=x + radius {
+                let offset = Vector2::new((cell_x - x) as f32, (cell_y - y) as f32);
+                let distance = offset.magnitude();
+                if distance > radius as f32 {
+                    continue;
+                }
+                match self.tiles.get(cell_x, cell_y) {
+                    Some(tile) if self.materials.get(tile.get().id).phase != Phase::Solid => {}
+                    _ => continue,
+                }
+                let away = if distance > 0.0 { offset / distance } else { up };
+                let speed = strength * (1.0 - distance / (radius as f32 + 1.0));
+                self.eject(cell_x, cell_y, away * speed);
+            }
+        }
+    }
+
+    // Moves every flying tile along its velocity as gravity pulls on it, and
+    // puts it back in the grid once it's about to hit a tile, the floor or a
+    // wall. Only the sky is open.
+    fn fly_particles(&mut self) {
+        let (width, height) = (self.dimensions.0 as i32, self.dimensions.1 as i32);
+        // Past an edge gravity pulls away from, a tile falls back in by
+        // itself. Past any other edge it would never come back, so it lands
+        // at the edge instead.
+        let gravity = self.gravity;
+        let stranded = |cell: i32, size: i32, pull: f32| (cell < 0 && pull <= 0.0) || (cell >= size && pull >= 0.0);
+        let mut landed = Vec::new();
+        for (index, particle) in self.particles.iter_mut().enumerate() {
+            particle.velocity += self.gravity;
+            let speed = particle.velocity.magnitude();
+            if speed > self.terminal_velocity {
+                particle.velocity *= self.terminal_velocity / speed;
+            }
+            // no more than a cell at a time so nothing is flown through
+            let moves = speed.min(self.terminal_velocity).ceil().max(1.0);
+            let step = particle.velocity / moves;
+            for _ in 0..moves as u32 {
+                let next = particle.position + step;
+                let (x, y) = (next.x.floor() as i32, next.y.floor() as i32);
+                let inside = (0..width).contains(&x) && (0..height).contains(&y);
+                if stranded(x, width, gravity.x) || stranded(y, height, gravity.y) || (inside && self.tiles.get(x, y).is_some()) {
+                    landed.push(index);
+                    break;
+                }
+                particle.position = next;
+            }
+        }
+        // backwards so swapping the last particle in doesn't move one that's
+        // still to come
+        for index in landed.into_iter().rev() {
+            let particle = self.particles[index];
+            let x = (particle.position.x.floor() as i32).max(0).min(width - 1);
+            let y = (particle.position.y.floor() as i32).max(0).min(height - 1);
+            // another tile may have landed here first
+            if let Some((x, y)) = self.nearest_free(x, y) {
+                self.particles.swap_remove(index);
+                self.tiles.insert(Tile { x: x as u32, y: y as u32, id: particle.id, life: particle.life, stepped: self.steps, velocity: particle.velocity });
+                self.heat.set(x, y, particle.temperature);
+                self.chunks.record(x, y);
+            }
+        }
+    }
+
+    // the empty cell closest to a cell, looking further and further out
+    fn nearest_free(&self, x: i32, y: i32) -> Option<(i32, i32)> {
+        let (width, height) = (self.dimensions.0 as i32, self.dimensions.1 as i32);
+        for reach in 0..width.max(height) {
+            for cell_y in (y - reach).max(0)..=(y + reach).min(height - 1) {
+                for cell_x in (x - reach).max(0)..=(x + reach).min(width - 1) {
+                    let on_ring = (cell_x - x).abs() == reach || (cell_y - y).abs() == reach;
+                    if on_ring && self.tiles.get(cell_x, cell_y).is_none() {
+                        return Some((cell_x, cell_y));
                     }
-                } else if tile.id == 2 {
-                    if self.tiles.get(tile.x as i32, tile.y as i32 - 1).is_some() {
-                        let direction = if self.random.gen::<f32>() > 0.5 { -1 } else { 1 };
-                        if self.tiles.get(tile.x as i32 + direction, tile.y as i32).is_none() {
-                            if (direction < 0 && tile.x > 0) || (direction > 0 && tile.x < self.dimensions.0 - 1) {
-                                self.tiles.swap(raw_tile, (tile.x, tile.y), (tile.x as i32 + direction, tile.y as i32));
-                            }
-                        } else if self.tiles.get(tile.x as i32 - direction, tile.y as i32).is_none() {
-                            if (-direction < 0 && tile.x > 0) || (-direction > 0 && tile.x < self.dimensions.0 - 1) {
-                                self.tiles.swap(raw_tile, (tile.x, tile.y), (tile.x as i32 - direction, tile.y as i32));
-                            }
+                }
+            }
+        }
+        None
+    }
+
+    // Adds a fresh tile of material `id` at its own temperature, unless the
+    // cell is taken. Returns whether it was added.
+    fn place(&mut self, x: i32, y: i32, id: u8) -> bool {
+        if x < 0 || y < 0 || self.tiles.get(x, y).is_some() {
+            return false;
+        }
+        let life = FallingSand::life(&self.materials, &mut self.random, id);
+        if self.tiles.insert(Tile { x: x as u32, y: y as u32, id, life, stepped: 0, velocity: Vector2::zero() }) {
+            self.heat.set(x, y, self.materials.get(id).temperature);
+            self.chunks.record(x, y);
+            true
+        } else {
+            false
+        }
+    }
+
+    // how long a new tile of material `id` lasts, a little random so a
+    // burst of fire doesn't go out all at once
+    fn life(materials: &MaterialTable, random: &mut StdRng, id: u8) -> u16 {
+        let lifetime = materials.get(id).lifetime;
+        if lifetime == 0 {
+            0
+        } else {
+            lifetime + random.gen_range(0, lifetime / 2 + 1)
+        }
+    }
+
+    // index into `DIRECTIONS` of the neighbour closest to the way gravity
+    // pulls
+    fn down(&self) -> Option<usize> {
+        if self.gravity.magnitude2() == 0.0 {
+            return None;
+        }
+        let turn = self.gravity.y.atan2(self.gravity.x) / std::f32::consts::FRAC_PI_4;
+        Some((turn.round() as i32).rem_euclid(8) as usize)
+    }
+
+    // blue below ambient, then red, yellow and white as it gets hotter
+    fn heat_color(temperature: f32, ambient: f32) -> [f32; 4] {
+        if temperature < ambient {
+            let cold = ((ambient - temperature) / 50.0).min(1.0);
+            [0.2, 0.4, 1.0, 0.8 * cold]
+        } else {
+            let hot = ((temperature - ambient) / 1200.0).min(1.0);
+            [1.0, (hot * 2.0 - 0.5).clamp(0.0, 1.0), (hot * 3.0 - 2.0).max(0.0), 0.3 + 0.6 * hot]
+        }
+    }
+}
+
+impl<'a> Step<'a> {
+    // Warms or cools a tile into another material, ages it and reacts it with
+    // at most one of the tiles next to it. A tile that ends up empty stays in
+    // place until the end of the step.
+    fn react(&self, random: &mut StdRng, raw_tile: &Cell<Tile>) {
+        let tile = raw_tile.get();
+        if tile.id == MaterialTable::EMPTY {
+            return;
+        }
+        let (x, y) = (tile.x as i32, tile.y as i32);
+        let material = self.materials.get(tile.id);
+        if material.flags.contains(Flags::HEAT_SOURCE) {
+            self.heat.set(x, y, material.temperature);
+        }
+        // melting and boiling keep the heat that caused them
+        if let Some(id) = self.materials.transition(tile.id, self.heat.get(x, y)) {
+            self.tiles.transform(raw_tile, id, FallingSand::life(self.materials, random, id));
+            self.chunks.record(x, y);
+            return;
+        }
+
+        if material.lifetime > 0 {
+            if tile.life <= 1 {
+                let id = self.materials.decays_into(tile.id);
+                self.transform(random, raw_tile, id);
+                return;
+            }
+            self.tiles.transform(raw_tile, tile.id, tile.life - 1);
+            // ageing keeps the chunk awake until the tile is gone
+            self.chunks.record(x, y);
+        }
+
+        for &(dx, dy) in &[(0, -1), (-1, 0), (1, 0), (0, 1)] {
+            let other = match self.tiles.get(x + dx, y + dy) {
+                Some(other) => other,
+                None => continue,
+            };
+            let other_id = other.get().id;
+            if other_id == MaterialTable::EMPTY {
+                continue;
+            }
+            if let Some(reaction) = self.materials.reaction(tile.id, other_id) {
+                if random.gen::<f32>() < reaction.chance {
+                    self.transform(random, raw_tile, reaction.into);
+                    self.transform(random, other, reaction.other_into);
+                    return;
+                }
+            }
+        }
+    }
+
+    // turns a tile into a fresh tile of material `id`, at that material's
+    // temperature
+    fn transform(&self, random: &mut StdRng, raw_tile: &Cell<Tile>, id: u8) {
+        let tile = raw_tile.get();
+        self.tiles.transform(raw_tile, id, FallingSand::life(self.materials, random, id));
+        self.heat.set(tile.x as i32, tile.y as i32, self.materials.get(id).temperature);
+        self.chunks.record(tile.x as i32, tile.y as i32);
+    }
+
+    // moves a tile and its heat, trading places with whatever is at `to`
+    fn swap(&self, raw_tile: &Cell<Tile>, to: (i32, i32)) {
+        let tile = raw_tile.get();
+        self.tiles.swap(raw_tile, (tile.x, tile.y), to);
+        self.heat.swap((tile.x as i32, tile.y as i32), to);
+        self.chunks.record(tile.x as i32, tile.y as i32);
+        self.chunks.wake(to.0, to.1);
+    }
+
+    // Moves one tile by the rules of its material's phase. Powders and
+    // liquids fly along their velocity as gravity speeds them up, and once
+    // stopped they fall a cell, straight down first and then diagonally.
+    // Liquids then flow sideways towards lower ground or are pushed up
+    // elsewhere by the liquid around them. Gases do the same upwards a cell
+    // at a time and drift sideways. Down is whichever way gravity pulls.
+    fn step_tile(&self, random: &mut StdRng, raw_tile: &Cell<Tile>) {
+        let tile = raw_tile.get();
+        let material = self.materials.get(tile.id);
+        let rising = match material.phase {
+            Phase::Solid => return,
+            Phase::Powder | Phase::Liquid => false,
+            Phase::Gas => true,
+        };
+        let down = match self.down {
+            Some(down) if rising => (down + 4) % 8,
+            Some(down) => down,
+            None => return,
+        };
+        if !rising && self.fly(random, raw_tile) {
+            return;
+        }
+        let (x, y) = (tile.x as i32, tile.y as i32);
+        // an eighth of a turn either way
+        let turn = if random.gen::<f32>() > 0.5 { 1 } else { 7 };
+
+        for &turn in &[0, turn, 8 - turn] {
+            let (dx, dy) = DIRECTIONS[(down + turn) % 8];
+            if self.gives_way(tile.id, (x + dx, y + dy), rising) {
+                self.swap(raw_tile, (x + dx, y + dy));
+                return;
+            }
+        }
+
+        // a quarter turn, the same way first
+        let turn = turn * 2 % 8;
+        if material.phase == Phase::Liquid {
+            if !self.flow(raw_tile, down, turn) && self.pressure {
+                self.press(raw_tile, down);
+            }
+        } else if material.phase == Phase::Gas {
+            for &turn in &[turn, 8 - turn] {
+                let (dx, dy) = DIRECTIONS[(down + turn) % 8];
+                let mut reach = 0;
+                while reach < material.dispersion as i32 && self.is_free(tile.id, (x + dx * (reach + 1), y + dy * (reach + 1))) {
+                    reach += 1;
+                }
+                if reach > 0 {
+                    self.swap(raw_tile, (x + dx * reach, y + dy * reach));
+                    return;
+                }
+            }
+        }
+    }
+
+    // Moves a liquid tile up to its dispersion sideways towards the nearest
+    // cell it could fall from, so liquids only spread while there's lower
+    // ground to spread to and lie still once they can't. Returns whether the
+    // tile moved.
+    fn flow(&self, raw_tile: &Cell<Tile>, down: usize, turn: usize) -> bool {
+        let tile = raw_tile.get();
+        let dispersion = self.materials.get(tile.id).dispersion as i32;
+        let (x, y) = (tile.x as i32, tile.y as i32);
+        let (fall_x, fall_y) = DIRECTIONS[down];
+        for &turn in &[turn, 8 - turn] {
+            let (dx, dy) = DIRECTIONS[(down + turn) % 8];
+            let mut reach = 0;
+            while reach < FLOW_REACH && self.is_free(tile.id, (x + dx * (reach + 1), y + dy * (reach + 1))) {
+                reach += 1;
+                let (edge_x, edge_y) = (x + dx * reach, y + dy * reach);
+                if self.gives_way(tile.id, (edge_x + fall_x, edge_y + fall_y), false) {
+                    let reach = reach.min(dispersion.max(1));
+                    self.swap(raw_tile, (x + dx * reach, y + dy * reach));
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    // Moves a tile on the surface of a body of liquid to the lowest empty
+    // cell touching the same body, if that's lower than the tile, the way
+    // the weight of the liquid would push it there. Every move lowers the
+    // liquid, so the surfaces settle once they're level. A body and the
+    // cells around it are found by the first of its tiles to push in a step
+    // and shared by the rest, which each take the nearest of the lowest
+    // cells left.
+    fn press(&self, raw_tile: &Cell<Tile>, down: usize) {
+        let tile = raw_tile.get();
+        let (x, y) = (tile.x as i32, tile.y as i32);
+        let (fall_x, fall_y) = DIRECTIONS[down];
+        // only the top of a column pushes, the rest is held under it
+        if let Some(above) = self.tiles.get(x - fall_x, y - fall_y) {
+            if above.get().id == tile.id {
+                return;
+            }
+        }
+        let height = |(x, y): (i32, i32)| -(x * fall_x + y * fall_y);
+        let bodies = &mut *self.bodies.borrow_mut();
+        let index = self.tiles.encoder.encode(x, y).unwrap();
+        let body = match bodies.labels[index] {
+            (step, body) if step == bodies.step && bodies.bodies[body].id == tile.id => body,
+            _ => self.find_body(bodies, (x, y), tile.id, height),
+        };
+        let body = &mut bodies.bodies[body];
+        // drops cells filled since the body was found
+        while body.next < body.end && self.tiles.get(bodies.outlets[body.next].0, bodies.outlets[body.next].1).is_some() {
+            body.next += 1;
+        }
+        if body.next == body.end || height(bodies.outlets[body.next]) >= height((x, y)) {
+            return;
+        }
+        // of the lowest cells, the one nearest the tile
+        let lowest = height(bodies.outlets[body.next]);
+        let distance = |(to_x, to_y): (i32, i32)| (to_x - x).abs() + (to_y - y).abs();
+        let mut nearest = body.next;
+        for index in body.next + 1..body.end {
+            let to = bodies.outlets[index];
+            if height(to) > lowest {
+                break;
+            }
+            if distance(to) < distance(bodies.outlets[nearest]) && self.tiles.get(to.0, to.1).is_none() {
+                nearest = index;
+            }
+        }
+        bodies.outlets.swap(body.next, nearest);
+        let to = bodies.outlets[body.next];
+        body.next += 1;
+        self.swap(raw_tile, to);
+        raw_tile.set(Tile { velocity: Vector2::zero(), ..raw_tile.get() });
+    }
+
+    // Labels the body of liquid `id` around `from` as a new body and lists
+    // the empty cells touching it, lowest first. Returns the new body.
+    fn find_body(&self, bodies: &mut Bodies, from: (i32, i32), id: u8, height: impl Fn((i32, i32)) -> i32) -> usize {
+        let body = bodies.bodies.len();
+        let label = (bodies.step, body);
+        let start = bodies.outlets.len();
+        let mut seen = 1;
+        bodies.labels[self.tiles.encoder.encode(from.0, from.1).unwrap()] = label;
+        bodies.queue.push_back(from);
+        while let Some((cell_x, cell_y)) = bodies.queue.pop_front() {
+            for &(dx, dy) in &[(0, -1), (-1, 0), (1, 0), (0, 1)] {
+                let next = (cell_x + dx, cell_y + dy);
+                let index = match self.tiles.encoder.encode(next.0, next.1) {
+                    Some(index) if bodies.labels[index] != label => index,
+                    _ => continue,
+                };
+                bodies.labels[index] = label;
+                seen += 1;
+                match self.tiles.get(next.0, next.1) {
+                    Some(other) if other.get().id == id => {
+                        if seen < PRESSURE_REACH {
+                            bodies.queue.push_back(next);
                         }
-                    } else {
-                        self.tiles.swap(raw_tile, (tile.x, tile.y), (tile.x as i32, tile.y as i32 - 1));
                     }
+                    Some(_) => {}
+                    None => bodies.outlets.push(next),
                 }
             }
         }
+        bodies.outlets[start..].sort_unstable_by_key(|&cell| height(cell));
+        bodies.bodies.push(Body { id, next: start, end: bodies.outlets.len() });
+        body
+    }
+
+    // Speeds a falling tile up by gravity and moves it along its velocity for
+    // as long as the cells on the way are empty. A lighter tile in the first
+    // cell is sunk through, which slows the tile down. A tile that hits
+    // something keeps only its sideways speed, and splashes if it hit hard.
+    // Returns whether the tile moved.
+    fn fly(&self, random: &mut StdRng, raw_tile: &Cell<Tile>) -> bool {
+        let tile = raw_tile.get();
+        let mut velocity = tile.velocity + self.gravity;
+        let speed = velocity.magnitude();
+        if speed > self.terminal_velocity {
+            velocity *= self.terminal_velocity / speed;
+        }
+        // the line of travel, a cell at a time along whichever axis it's
+        // steeper in
+        let reach = velocity.x.abs().max(velocity.y.abs());
+        if reach == 0.0 {
+            return false;
+        }
+        let step = velocity / reach;
+        let (x, y) = (tile.x as i32, tile.y as i32);
+        let mut to = None;
+        let mut hit = false;
+        for cell in 1..=reach.round().max(1.0) as i32 {
+            let next = (x + (step.x * cell as f32).round() as i32, y + (step.y * cell as f32).round() as i32);
+            if self.tiles.encoder.encode(next.0, next.1).is_some() && self.tiles.get(next.0, next.1).is_none() {
+                to = Some(next);
+                continue;
+            }
+            if cell == 1 && self.gives_way(tile.id, next, false) {
+                self.swap(raw_tile, next);
+                raw_tile.set(Tile { velocity: velocity * 0.5, ..raw_tile.get() });
+                return true;
+            }
+            hit = true;
+            break;
+        }
+        if hit {
+            velocity = self.land(random, tile.id, velocity);
+        }
+        match to {
+            Some(to) => {
+                self.swap(raw_tile, to);
+                raw_tile.set(Tile { velocity, ..raw_tile.get() });
+                true
+            }
+            None => {
+                raw_tile.set(Tile { velocity, ..tile });
+                false
+            }
+        }
+    }
+
+    // what's left of a tile's velocity after hitting something on its way
+    // down, liquids sliding further and splashing harder than powders
+    fn land(&self, random: &mut StdRng, id: u8, velocity: Vector2<f32>) -> Vector2<f32> {
+        let (friction, splash) = match self.materials.get(id).phase {
+            Phase::Liquid => (0.9, 0.5),
+            _ => (0.5, 0.2),
+        };
+        let down = self.gravity.normalize();
+        let impact = velocity.dot(down);
+        let mut sideways = (velocity - down * impact) * friction;
+        if impact > SPLASH_SPEED {
+            let across = Vector2::new(-down.y, down.x);
+            let side = if random.gen::<f32>() > 0.5 { 1.0 } else { -1.0 };
+            sideways += across * side * impact * splash;
+        }
+        if sideways.magnitude() < SETTLE_SPEED {
+            Vector2::zero()
+        } else {
+            sideways
+        }
+    }
+
+    // whether a tile of material `id` can move vertically into `to`
+    fn gives_way(&self, id: u8, to: (i32, i32), rising: bool) -> bool {
+        if self.tiles.encoder.encode(to.0, to.1).is_none() {
+            return false;
+        }
+        match self.tiles.get(to.0, to.1) {
+            None => true,
+            Some(other) => {
+                let (material, other) = (self.materials.get(id), self.materials.get(other.get().id));
+                if rising {
+                    material.rises_through(other)
+                } else {
+                    material.sinks_through(other)
+                }
+            }
+        }
+    }
+
+    // whether a tile of material `id` can spread sideways into `to`, only
+    // empty cells and lighter gases make room
+    fn is_free(&self, id: u8, to: (i32, i32)) -> bool {
+        if self.tiles.encoder.encode(to.0, to.1).is_none() {
+            return false;
+        }
+        match self.tiles.get(to.0, to.1) {
+            None => true,
+            Some(other) => {
+                let other = self.materials.get(other.get().id);
+                other.phase == Phase::Gas && self.materials.get(id).density > other.density
+            }
+        }
+    }
+}
+
+impl Simulation for FallingSand {
+    fn update(&mut self) {
+        self.chunks.begin_step();
+        self.bodies.get_mut().begin_step();
+        let mut pours = Vec::new();
+        for (_, emitter, material) in &mut self.emitters {
+            for (x, y) in emitter.emit(&mut self.random) {
+                pours.push((x, y, *material));
+            }
+        }
+        for (x, y, material) in pours {
+            // skips cells still full from the last pour
+            self.place(x, y, material);
+        }
+        self.emitters.retain(|(_, emitter, _)| !emitter.is_spent());
+        self.fly_particles();
+
+        let down = self.down();
+        let step = Step {
+            tiles: &self.tiles,
+            materials: &self.materials,
+            heat: &self.heat,
+            chunks: &self.chunks,
+            gravity: self.gravity,
+            terminal_velocity: self.terminal_velocity,
+            pressure: self.pressure,
+            down,
+            bodies: &self.bodies,
+        };
+        // Cells are visited from the bottom, the way gravity pulls, so a
+        // falling tile never lands in a row that's still to come. Each row runs
+        // the other way to the one below it and to the same row last step so
+        // neither side is favoured, unless gravity pulls to one side, when rows
+        // start from that side. Tiles that rise or spread into cells still to
+        // come are marked so they don't move twice.
+        self.steps = self.steps.wrapping_add(1);
+        let (width, height) = (self.dimensions.0 as i32, self.dimensions.1 as i32);
+        let chunk_size = CHUNK_SIZE as i32;
+        let (down_x, down_y) = DIRECTIONS[down.unwrap_or(6)];
+        for row in 0..height {
+            let y = if down_y > 0 { height - 1 - row } else { row };
+            let leftwards = match down_x {
+                0 => (y as u32 + self.steps) % 2 == 1,
+                down_x => down_x > 0,
+            };
+            let chunk_count = self.chunks.columns() as i32;
+            for chunk in 0..chunk_count {
+                let chunk = if leftwards { chunk_count - 1 - chunk } else { chunk };
+                let x0 = chunk * chunk_size;
+                if !self.chunks.is_awake(x0, y) {
+                    continue;
+                }
+                let x1 = (x0 + chunk_size).min(width);
+                for x in x0..x1 {
+                    let x = if leftwards { x0 + x1 - 1 - x } else { x };
+                    let raw_tile = match self.tiles.get(x, y) {
+                        Some(raw_tile) if raw_tile.get().stepped != self.steps => raw_tile,
+                        _ => continue,
+                    };
+                    raw_tile.set(Tile { stepped: self.steps, ..raw_tile.get() });
+                    step.react(&mut self.random, raw_tile);
+                    if raw_tile.get().id != MaterialTable::EMPTY {
+                        step.step_tile(&mut self.random, raw_tile);
+                    }
+                }
+            }
+        }
+
+        let (width, height) = self.dimensions;
+        for (_, sink) in &mut self.sinks {
+            for (x, y) in sink.drain(width, height) {
+                if self.tiles.remove_at(x, y) {
+                    self.chunks.record(x, y);
+                }
+            }
+        }
+        self.sinks.retain(|(_, sink)| !sink.is_spent());
+
+        // tiles that burnt out or dissolved this step, backwards so the tile
+        // swapped into a removed one's place has already been checked
+        for index in (0..self.tiles.tiles.len()).rev() {
+            if self.tiles.tiles[index].get().id == MaterialTable::EMPTY {
+                self.tiles.remove(index);
+            }
+        }
+
+        // heat keeps spreading in a chunk until it evens out, then lets it
+        // sleep
+        let awake = self.chunks.awake_chunks();
+        let regions: Vec<_> = awake.iter().map(|&chunk| self.chunks.region(chunk)).collect();
+        let (tiles, materials) = (&self.tiles, &self.materials);
+        let changing = self.heat.diffuse(&regions, |index| match tiles.tilemap[index].get() {
+            Some(tile) => (materials.get(tiles.tiles[tile].get().id).conductivity, false),
+            None => (materials.get(MaterialTable::EMPTY).conductivity, true),
+        });
+        for (&chunk, changing) in awake.iter().zip(changing) {
+            if changing {
+                self.chunks.wake_around(chunk);
+            }
+        }
+
+        if self.debug {
+            if let Err(error) = self.tiles.check_invariants() {
+                panic!("broken tile storage: {}", error);
+            }
+        }
     }
 
     fn render(&self, gl: &GL) {
+        let renderer = match &self.renderer {
+            Some(renderer) => renderer,
+            None => return,
+        };
+        let width = 2.0 / self.dimensions.0 as f32;
+        let height = 2.0 / self.dimensions.1 as f32;
         let mut instances = Vec::<Instance>::with_capacity(self.tiles.tiles.len());
-        self.renderer.bind(gl);
-        for tile in &self.tiles.tiles {
+        renderer.bind(gl);
+        for (index, tile) in self.tiles.tiles.iter().enumerate() {
             let tile = tile.get();
-            let width = 2.0 / self.dimensions.0 as f32;
-            let height = 2.0 / self.dimensions.1 as f32;
             let x: f32 = width * tile.x as f32 - 1.0;
             let y: f32 = height * tile.y as f32 - 1.0;
-            let color: [f32; 4] = if tile.id == 1 {
-                [237.0 / 256.0, 201.0 / 256.0, 175.0 / 256.0, 1.0]
-            } else if tile.id == 2 {
-                [0.0, 0.41, 0.58, 1.0]
-            } else {
-                [0.0,0.0,0.0,0.0]
-            };
+            let color = self.materials.get(tile.id).color(index);
 
             instances.push(Instance {
                 x,
@@ -185,6 +1122,528 @@ impl Simulation for FallingSand {
                 color,
             });
         }
-        self.renderer.render_instances(gl, instances);
+
+        if self.show_heat {
+            let ambient = self.heat.ambient();
+            for y in 0..self.dimensions.1 as i32 {
+                for x in 0..self.dimensions.0 as i32 {
+                    let temperature = self.heat.get(x, y);
+                    // leave cells near ambient alone so the world shows through
+                    if (temperature - ambient).abs() < 2.0 {
+                        continue;
+                    }
+                    instances.push(Instance {
+                        x: width * x as f32 - 1.0,
+                        y: height * y as f32 - 1.0,
+                        width,
+                        height,
+                        angle: 0.0,
+                        color: FallingSand::heat_color(temperature, ambient),
+                    });
+                }
+            }
+        }
+        for (index, particle) in self.particles.iter().enumerate() {
+            instances.push(Instance {
+                x: width * (particle.position.x - 0.5) - 1.0,
+                y: height * (particle.position.y - 0.5) - 1.0,
+                width,
+                height,
+                angle: 0.0,
+                color: self.materials.get(particle.id).color(index),
+            });
+        }
+        renderer.render_instances(gl, instances);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tile(x: u32, y: u32, id: u8) -> Tile {
+        Tile { x, y, id, life: 0, stepped: 0, velocity: Vector2::zero() }
+    }
+
+    #[test]
+    fn insert_refuses_taken_and_outside_cells() {
+        let mut tiles = TileStorage::new(4, 4);
+        assert!(tiles.insert(tile(1, 1, MaterialTable::SAND)));
+        assert!(!tiles.insert(tile(1, 1, MaterialTable::WATER)));
+        assert!(!tiles.insert(tile(4, 0, MaterialTable::SAND)));
+        assert_eq!(tiles.len(), 1);
+        assert_eq!(tiles.get(1, 1).unwrap().get().id, MaterialTable::SAND);
+        tiles.check_invariants().unwrap();
+    }
+
+    #[test]
+    fn remove_keeps_the_map_in_step() {
+        let mut tiles = TileStorage::new(4, 4);
+        for x in 0..4 {
+            tiles.insert(tile(x, 0, MaterialTable::SAND));
+        }
+        // the last tile is swapped into the removed one's place
+        tiles.remove(1);
+        tiles.check_invariants().unwrap();
+        assert!(tiles.get(1, 0).is_none());
+        assert_eq!(tiles.get(3, 0).unwrap().get().x, 3);
+
+        assert!(tiles.remove_at(3, 0));
+        assert!(!tiles.remove_at(3, 0));
+        // removing the last tile has nothing to swap in
+        let last = tiles.len() - 1;
+        tiles.remove(last);
+        tiles.check_invariants().unwrap();
+        assert_eq!(tiles.len(), 1);
+    }
+
+    #[test]
+    fn transform_changes_type_in_place() {
+        let mut tiles = TileStorage::new(4, 4);
+        tiles.insert(tile(2, 3, MaterialTable::WATER));
+        tiles.transform(tiles.get(2, 3).unwrap(), MaterialTable::STEAM, 10);
+        let changed = tiles.get(2, 3).unwrap().get();
+        assert_eq!((changed.x, changed.y, changed.id, changed.life), (2, 3, MaterialTable::STEAM, 10));
+        tiles.check_invariants().unwrap();
+    }
+
+    #[test]
+    fn clear_empties_every_cell() {
+        let mut tiles = TileStorage::new(4, 4);
+        tiles.insert(tile(0, 0, MaterialTable::SAND));
+        tiles.insert(tile(3, 3, MaterialTable::SAND));
+        tiles.clear();
+        assert_eq!(tiles.len(), 0);
+        assert!(tiles.get(0, 0).is_none() && tiles.get(3, 3).is_none());
+        tiles.check_invariants().unwrap();
+    }
+
+    #[test]
+    fn invariants_catch_a_stale_map() {
+        let mut tiles = TileStorage::new(4, 4);
+        tiles.insert(tile(0, 0, MaterialTable::SAND));
+        tiles.tiles[0].set(tile(1, 0, MaterialTable::SAND));
+        assert!(tiles.check_invariants().is_err());
+    }
+
+    #[test]
+    fn reactions_keep_storage_consistent() {
+        let mut sand = FallingSand::headless(32, 32, 1);
+        sand.set_debug(true);
+        sand.paint(8, 4, 4, MaterialTable::WOOD);
+        sand.paint(8, 12, 2, MaterialTable::FIRE);
+        sand.paint(24, 20, 3, MaterialTable::WATER);
+        sand.paint(24, 10, 3, MaterialTable::LAVA);
+        sand.paint(16, 28, 3, MaterialTable::ACID);
+        for _ in 0..500 {
+            sand.update();
+            sand.tiles.check_invariants().unwrap();
+        }
+        // tiles that burn out or dissolve are gone by the end of the step
+        assert!(sand.tiles.tiles.iter().all(|tile| tile.get().id != MaterialTable::EMPTY));
+    }
+
+    fn count(sand: &FallingSand, id: u8) -> usize {
+        sand.tiles.tiles.iter().filter(|tile| tile.get().id == id).count()
+    }
+
+    #[test]
+    fn heat_melts_and_freezes() {
+        let mut sand = FallingSand::headless(40, 40, 3);
+        sand.paint(30, 5, 3, MaterialTable::ICE);
+        sand.paint(10, 30, 3, MaterialTable::LAVA);
+        let (ice, lava) = (count(&sand, MaterialTable::ICE), count(&sand, MaterialTable::LAVA));
+        for _ in 0..300 {
+            sand.update();
+        }
+        // the ambient air warms the ice and cools the lava
+        assert_eq!(count(&sand, MaterialTable::WATER), ice);
+        assert_eq!(count(&sand, MaterialTable::STONE), lava);
+    }
+
+    #[test]
+    fn heat_follows_moving_tiles() {
+        let mut sand = FallingSand::headless(8, 8, 0);
+        sand.paint(4, 7, 0, MaterialTable::SAND);
+        sand.heat.set(4, 7, 500.0);
+        sand.update();
+        let tile = sand.tiles.tiles[0].get();
+        assert_eq!((tile.x, tile.y), (4, 6));
+        assert!(sand.temperature(4, 6) > 400.0);
+    }
+
+    #[test]
+    fn heat_sources_boil_water() {
+        let mut sand = FallingSand::headless(20, 20, 5);
+        let heater = sand
+            .add_material_json(r#"{"name": "heater", "density": 5, "phase": "solid", "colors": [],
+                "flags": ["heat_source"], "temperature": 300, "conductivity": 1}"#)
+            .unwrap();
+        for x in 0..20 {
+            sand.place(x, 0, if x == 10 { heater } else { MaterialTable::STONE });
+        }
+        // a cup one cell wide over the heater
+        sand.place(9, 1, MaterialTable::STONE);
+        sand.place(11, 1, MaterialTable::STONE);
+        sand.place(10, 1, MaterialTable::WATER);
+        for _ in 0..10 {
+            sand.update();
+        }
+        assert_eq!(count(&sand, MaterialTable::WATER), 0);
+        assert_eq!(count(&sand, MaterialTable::STEAM), 1);
+    }
+
+    #[test]
+    fn overconductive_materials_keep_heat_in_bounds() {
+        let mut sand = FallingSand::headless(20, 20, 6);
+        let copper = sand
+            .add_material_json(r#"{"name": "copper", "density": 9, "phase": "solid", "colors": [],
+                "temperature": 20, "conductivity": 50}"#)
+            .unwrap();
+        for x in 0..20 {
+            for y in 0..5 {
+                sand.place(x, y, copper);
+            }
+        }
+        sand.heat.set(10, 2, 500.0);
+        for _ in 0..50 {
+            sand.update();
+            for x in 0..20 {
+                for y in 0..20 {
+                    let temperature = sand.temperature(x, y);
+                    assert!((20.0..=500.0).contains(&temperature), "{} at ({}, {})", temperature, x, y);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn settled_chunks_sleep_until_disturbed() {
+        let mut sand = FallingSand::headless(256, 128, 2);
+        for x in 0..256 {
+            sand.place(x, 0, MaterialTable::STONE);
+        }
+        sand.paint(32, 40, 10, MaterialTable::SAND);
+        let total = sand.stats().columns * sand.stats().rows;
+        for _ in 0..600 {
+            sand.update();
+        }
+        let stats = sand.stats();
+        assert_eq!(stats.awake, 0);
+        assert!(stats.activity().iter().all(|&activity| activity == 0));
+
+        // digging out from under the pile wakes only the chunks around it
+        sand.paint(32, 1, 2, MaterialTable::EMPTY);
+        sand.update();
+        let stats = sand.stats();
+        assert!(stats.awake > 0 && stats.awake < total);
+        assert!(stats.activity()[32 / CHUNK_SIZE as usize] > 0);
+    }
+
+    // height of the tallest tile in each column, 0 for an empty column
+    fn heights(sand: &FallingSand) -> Vec<u32> {
+        let mut heights = vec![0; sand.dimensions.0 as usize];
+        for tile in &sand.tiles.tiles {
+            let tile = tile.get();
+            heights[tile.x as usize] = heights[tile.x as usize].max(tile.y + 1);
+        }
+        heights
+    }
+
+    #[test]
+    fn poured_sand_piles_up_symmetrically() {
+        let mut sand = FallingSand::headless(121, 80, 3);
+        for _ in 0..1500 {
+            sand.place(60, 79, MaterialTable::SAND);
+            sand.update();
+        }
+        // let the last of the stream and its splashes land
+        for _ in 0..100 {
+            sand.update();
+        }
+        let heights = heights(&sand);
+        let left: u32 = heights[..60].iter().sum();
+        let right: u32 = heights[61..].iter().sum();
+        assert!(left > 0);
+        assert!((left as f32 - right as f32).abs() < 0.05 * (left + right) as f32, "{} left, {} right", left, right);
+        // the slopes mirror each other, give or take a grain
+        for offset in 1..60 {
+            let (left, right) = (heights[60 - offset] as i32, heights[60 + offset] as i32);
+            assert!((left - right).abs() <= 2, "{} and {} at {} out", left, right, offset);
+        }
+    }
+
+    #[test]
+    fn tiles_move_at_most_once_a_step() {
+        let mut sand = FallingSand::headless(16, 16, 4);
+        sand.place(8, 2, MaterialTable::STEAM);
+        sand.place(3, 12, MaterialTable::SAND);
+        sand.update();
+        // one cell up and one cell down, not all the way in a single sweep
+        let steam = sand.tiles.get(8, 3).or_else(|| sand.tiles.get(7, 3)).or_else(|| sand.tiles.get(9, 3));
+        assert_eq!(steam.map(|tile| tile.get().id), Some(MaterialTable::STEAM));
+        assert_eq!(sand.tiles.get(3, 11).map(|tile| tile.get().id), Some(MaterialTable::SAND));
+    }
+
+    #[test]
+    fn released_water_spreads_evenly() {
+        // painted tiles are stored row by row from the left, which used to
+        // send the water drifting left
+        let (mut left, mut right) = (0, 0);
+        for seed in 0..3 {
+            let mut sand = FallingSand::headless(121, 80, seed);
+            sand.paint(60, 30, 12, MaterialTable::WATER);
+            for _ in 0..60 {
+                sand.update();
+            }
+            left += sand.tiles.tiles.iter().filter(|tile| tile.get().x < 60).count();
+            right += sand.tiles.tiles.iter().filter(|tile| tile.get().x > 60).count();
+        }
+        assert!((left as f32 - right as f32).abs() < 0.05 * (left + right) as f32, "{} left, {} right", left, right);
+    }
+
+    #[test]
+    fn emitters_pour_at_their_rate_until_spent() {
+        let mut sand = FallingSand::headless(64, 64, 5);
+        let id = sand.add_emitter_json(r#"{"material": "sand", "x": 32, "y": 60, "rate": 0.5, "lifetime": 7}"#).unwrap();
+        for _ in 0..10 {
+            sand.update();
+        }
+        // a tile every other step, then nothing once the lifetime is up
+        assert_eq!(count(&sand, MaterialTable::SAND), 3);
+        assert!(!sand.remove_emitter(id));
+
+        assert!(sand.add_emitter_json(r#"{"material": "glitter", "x": 0, "y": 0}"#).is_err());
+        let id = sand.add_emitter(Emitter::new("water", 10, 60)).unwrap();
+        sand.update();
+        assert!(sand.remove_emitter(id));
+        sand.update();
+        assert_eq!(count(&sand, MaterialTable::WATER), 1);
+    }
+
+    #[test]
+    fn sinks_delete_tiles_that_reach_them() {
+        let mut sand = FallingSand::headless(32, 32, 6);
+        sand.load_scene_json(r#"{
+            "emitters": [{"material": "sand", "x": 16, "y": 30, "spread": 1}],
+            "sinks": [{"x": 12, "y": 0, "shape": {"type": "rectangle", "width": 9, "height": 1}}]
+        }"#).unwrap();
+        for _ in 0..200 {
+            sand.update();
+        }
+        // everything that fell is drained, only the stream is left
+        assert!(sand.len() < 40, "{} tiles left", sand.len());
+        assert!(sand.tiles.tiles.iter().all(|tile| tile.get().y > 0));
+
+        assert!(sand.load_scene_json(r#"{"emitters": [{"material": "glitter", "x": 0, "y": 0}]}"#).is_err());
+        assert_eq!(sand.emitters.len(), 1);
+        assert!(sand.remove_sink(0));
+    }
+
+    #[test]
+    fn falling_tiles_speed_up_to_terminal_velocity() {
+        let mut sand = FallingSand::headless(8, 400, 7);
+        sand.place(4, 399, MaterialTable::SAND);
+        let mut heights = vec![399];
+        for _ in 0..85 {
+            sand.update();
+            heights.push(sand.tiles.tiles[0].get().y);
+        }
+        let falls: Vec<u32> = heights.windows(2).map(|pair| pair[0] - pair[1]).collect();
+        assert_eq!(falls[0], 1);
+        assert!(falls.windows(2).all(|pair| pair[1] >= pair[0]), "{:?}", falls);
+        assert_eq!(*falls.last().unwrap(), 8);
+    }
+
+    #[test]
+    fn fast_tiles_stop_at_thin_floors() {
+        let mut sand = FallingSand::headless(8, 300, 8);
+        sand.set_terminal_velocity(20.0);
+        for x in 0..8 {
+            sand.place(x, 10, MaterialTable::STONE);
+        }
+        sand.place(4, 299, MaterialTable::SAND);
+        for _ in 0..200 {
+            sand.update();
+        }
+        let grain = sand.tiles.tiles.iter().map(Cell::get).find(|tile| tile.id == MaterialTable::SAND).unwrap();
+        assert_eq!(grain.y, 11);
+    }
+
+    #[test]
+    fn hard_landings_splash() {
+        let mut sand = FallingSand::headless(64, 200, 9);
+        sand.place(32, 199, MaterialTable::SAND);
+        for _ in 0..200 {
+            sand.update();
+        }
+        let grain = sand.tiles.tiles[0].get();
+        assert_eq!(grain.y, 0);
+        assert_ne!(grain.x, 32);
+    }
+
+    #[test]
+    fn gravity_can_pull_sideways() {
+        let mut sand = FallingSand::headless(32, 32, 10);
+        sand.set_gravity(0.1, 0.0);
+        sand.paint(8, 16, 4, MaterialTable::SAND);
+        for _ in 0..100 {
+            sand.update();
+        }
+        // piled up against the right wall instead of the floor
+        assert!(sand.tiles.tiles.iter().all(|tile| tile.get().x >= 26));
+        assert!(sand.tiles.tiles.iter().any(|tile| tile.get().x == 31));
+
+        sand.set_gravity(0.0, 0.0);
+        let before: Vec<_> = sand.tiles.tiles.iter().map(|tile| (tile.get().x, tile.get().y)).collect();
+        sand.update();
+        let after: Vec<_> = sand.tiles.tiles.iter().map(|tile| (tile.get().x, tile.get().y)).collect();
+        assert_eq!(before, after);
+    }
+
+    // tiles of each material, flying or not
+    fn census(sand: &FallingSand) -> Vec<usize> {
+        let mut counts = vec![0; sand.materials.len()];
+        for tile in &sand.tiles.tiles {
+            counts[tile.get().id as usize] += 1;
+        }
+        for particle in &sand.particles {
+            counts[particle.id as usize] += 1;
+        }
+        counts
+    }
+
+    #[test]
+    fn explosions_conserve_material() {
+        let mut sand = FallingSand::headless(96, 96, 11);
+        for x in 0..96 {
+            sand.place(x, 0, MaterialTable::STONE);
+        }
+        sand.paint(30, 10, 8, MaterialTable::SAND);
+        sand.paint(60, 10, 8, MaterialTable::WATER);
+        sand.paint(45, 30, 3, MaterialTable::STONE);
+        for _ in 0..50 {
+            sand.update();
+        }
+        let before = census(&sand);
+        sand.explode(45, 6, 20, 6.0);
+        assert!(sand.particle_count() > 0);
+        // the floor and the solid block stay put
+        assert_eq!(count(&sand, MaterialTable::STONE), before[MaterialTable::STONE as usize]);
+        for _ in 0..300 {
+            sand.update();
+            assert_eq!(census(&sand), before);
+        }
+        assert_eq!(sand.particle_count(), 0);
+        assert_eq!(sand.stats().particles, 0);
+    }
+
+    #[test]
+    fn flying_tiles_land_whichever_way_gravity_pulls() {
+        for &(x, y) in &[(0.0, 0.3), (0.0, 0.0), (0.3, 0.0), (-0.2, -0.2)] {
+            let mut sand = FallingSand::headless(64, 48, 13);
+            sand.paint(32, 24, 8, MaterialTable::SAND);
+            sand.paint(32, 24, 3, MaterialTable::STONE);
+            let before = census(&sand);
+            sand.explode(32, 24, 10, 4.0);
+            assert!(sand.particle_count() > 0);
+            sand.set_gravity(x, y);
+            for _ in 0..300 {
+                sand.update();
+                assert_eq!(census(&sand), before);
+            }
+            assert_eq!(sand.particle_count(), 0);
+        }
+    }
+
+    #[test]
+    fn ejected_tiles_land_back_in_the_grid() {
+        let mut sand = FallingSand::headless(32, 64, 12);
+        for x in 0..32 {
+            sand.place(x, 9, MaterialTable::STONE);
+        }
+        sand.place(16, 10, MaterialTable::SAND);
+        assert!(!sand.eject(16, 11, Vector2::new(0.0, 1.5)));
+        assert!(sand.eject(16, 10, Vector2::new(0.0, 1.5)));
+        assert!(sand.tiles.get(16, 10).is_none());
+        sand.update();
+        assert!(sand.particles[0].position.y > 11.0);
+        for _ in 0..100 {
+            sand.update();
+        }
+        // straight up and back down where it was
+        assert_eq!(sand.particle_count(), 0);
+        assert_eq!(sand.tiles.get(16, 10).map(|tile| tile.get().id), Some(MaterialTable::SAND));
+
+        // thrown over the top and sideways into the wall
+        assert!(sand.eject(16, 10, Vector2::new(8.0, 8.0)));
+        for _ in 0..100 {
+            sand.update();
+        }
+        assert_eq!(sand.particle_count(), 0);
+        assert_eq!(count(&sand, MaterialTable::SAND), 1);
+    }
+
+    // A stone U with two arms joined under a divider, and water filling the
+    // left arm. Returns the highest water in each arm.
+    fn fill_u_tube(pressure: bool) -> (u32, u32) {
+        let mut sand = FallingSand::headless(64, 64, 13);
+        sand.set_pressure(pressure);
+        for x in 10..=50 {
+            sand.place(x, 0, MaterialTable::STONE);
+        }
+        for y in 1..50 {
+            sand.place(10, y, MaterialTable::STONE);
+            sand.place(50, y, MaterialTable::STONE);
+            if y > 5 {
+                sand.place(30, y, MaterialTable::STONE);
+            }
+        }
+        for y in 1..40 {
+            for x in 11..30 {
+                sand.place(x, y, MaterialTable::WATER);
+            }
+        }
+        for _ in 0..1500 {
+            sand.update();
+        }
+        let surface = |arm: std::ops::Range<u32>| {
+            sand.tiles.tiles.iter().map(Cell::get)
+                .filter(|tile| tile.id == MaterialTable::WATER && arm.contains(&tile.x))
+                .map(|tile| tile.y)
+                .max()
+                .unwrap_or(0)
+        };
+        (surface(11..30), surface(31..50))
+    }
+
+    #[test]
+    fn water_levels_out_in_a_u_tube() {
+        let (left, right) = fill_u_tube(true);
+        assert!(left > 10 && (left as i32 - right as i32).abs() <= 1, "{} left, {} right", left, right);
+
+        // without pressure the water can't climb the right arm
+        let (left, right) = fill_u_tube(false);
+        assert!(left > right + 10, "{} left, {} right", left, right);
+    }
+
+    #[test]
+    fn still_water_settles_and_sleeps() {
+        let mut sand = FallingSand::headless(128, 64, 14);
+        for x in 0..128 {
+            sand.place(x, 0, MaterialTable::STONE);
+        }
+        sand.paint(64, 20, 10, MaterialTable::WATER);
+        let mut slept = false;
+        for _ in 0..1500 {
+            sand.update();
+            if sand.stats().awake == 0 {
+                slept = true;
+                break;
+            }
+        }
+        assert!(slept);
+        // spread into a pool no deeper than it needs to be
+        assert!(sand.tiles.tiles.iter().all(|tile| tile.get().y <= 3));
     }
 }
@@ -2,173 +2,531 @@ use crate::rendering::Instance;
 use cgmath::prelude::*;
 use rand::prelude::*;
 use std::ops::{Add, Div, DivAssign, Mul};
+use wasm_bindgen::prelude::*;
 use web_sys::WebGlRenderingContext as GL;
 
 use crate::{
-    quadtree::Quadtree, quadtree::Rectangle as Rect, rendering::Rectangle, rendering::Triangle,
-    utils::ScreenSpaceEncoder,
+    quadtree::Bounds, quadtree::LooseQuadtree, quadtree::Quadtree, quadtree::Rectangle as Rect,
+    rendering::Rectangle, rendering::Triangle, simulations::metrics, simulations::orca,
+    simulations::rules, simulations::steering, simulations::Behaviour, simulations::BoidBuffer,
+    simulations::BoidState, simulations::FlockMetrics, simulations::Neighbour,
+    simulations::Obstacle, simulations::OrcaAgent, simulations::Trails, simulations::VectorField,
+    utils::limit, utils::set_magnitude, utils::thread_pool, utils::ScreenSpaceEncoder,
+    utils::SplitMix64,
 };
 
+// thickness of trail segments in clip space, same as the quadtree lines
+const TRAIL_WIDTH: f32 = 0.004;
+// spacing in pixels of the arrows drawn when debugging vector fields
+const FIELD_ARROW_SPACING: f32 = 40.0;
+
+/// How boids of one species react to boids of another.
+#[wasm_bindgen]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Interaction {
+    /// Align, cohere and keep separation, the classic flocking rules.
+    Flock,
+    /// Steer away, the other species is a predator.
+    Flee,
+    /// Steer towards the nearest one, the other species is prey.
+    Chase,
+    Ignore,
+}
+
+/// What happens to boids at the edges of the canvas.
+#[wasm_bindgen]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum BoundaryMode {
+    /// Space is a torus, boids leaving one side enter on the other.
+    Wrap,
+    /// Boids reflect off the edges.
+    Bounce,
+    /// Boids turn back once inside the margin and are held on screen.
+    Steer,
+    /// Boids leaving the canvas are respawned on a random edge heading inwards.
+    Respawn,
+}
+
+/// How boids pick the neighbours they react to.
+#[wasm_bindgen]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum NeighbourMode {
+    /// Every boid within the perception radius.
+    Metric,
+    /// A fixed number of the nearest boids however far away they are, the
+    /// way starlings track about seven others.
+    Topological,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Boundary {
+    pub mode: BoundaryMode,
+    /// Distance from the edges in pixels where `Steer` starts turning boids.
+    pub margin: f32,
+}
+
+/// Perception parameters shared by the whole flock. Every rule has its own
+/// radius and nothing inside the blind cone behind a boid is seen, which with
+/// a separation radius smaller than the others gives the classic Reynolds model.
+#[derive(Debug, Copy, Clone)]
+pub struct Perception {
+    pub alignment_radius: f32,
+    pub cohesion_radius: f32,
+    pub seperation_radius: f32,
+    /// Full angle in radians of the blind cone centered behind the heading.
+    pub blind_angle: f32,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Species {
+    pub color: [f32; 4],
+    pub size: f32,
+    pub max_speed: f32,
+}
+
+/// Per-boid parameters. The changing state lives in the flock's `BoidBuffer`s.
 #[derive(Debug, Copy, Clone)]
 pub struct Boid {
-    pub position: cgmath::Vector2<f32>,
-    pub velocity: cgmath::Vector2<f32>,
-    pub acceleration: cgmath::Vector2<f32>,
     pub alignment_force: f32,
     pub cohesion_force: f32,
     pub seperation_force: f32,
+    pub flee_force: f32,
+    pub chase_force: f32,
+    pub avoidance_force: f32,
+    /// Largest force any one of the flock's steering behaviours may apply.
+    pub steering_force: f32,
+    pub alignment_radius: f32,
+    pub cohesion_radius: f32,
+    pub seperation_radius: f32,
+    pub blind_angle: f32,
+    /// Radius of the neighbourhood query, the largest of the rule radii.
     pub perception_size: f32,
     pub max_speed: f32,
+    pub species: usize,
     pub index: usize,
 }
 
 impl Boid {
-    pub fn update(&mut self, width: i32, height: i32, sensed_boids: &Vec<(Boid, f32)>) {
-        self.acceleration *= 0.0;
+    pub fn new(index: usize) -> Self {
+        Self {
+            alignment_force: 0.4,
+            cohesion_force: 0.2,
+            seperation_force: 0.4,
+            flee_force: 0.6,
+            chase_force: 0.3,
+            avoidance_force: 0.8,
+            steering_force: 0.3,
+            alignment_radius: 75.0 / 2.0,
+            cohesion_radius: 75.0 / 2.0,
+            seperation_radius: 50.0 / 1.75,
+            blind_angle: 0.0,
+            perception_size: 75.0 / 2.0,
+            max_speed: 7.0 / 2.0,
+            species: 0,
+            index,
+        }
+    }
 
-        let alignment = self.align(sensed_boids);
-        let cohesion = self.cohesion(sensed_boids);
-        let seperation = self.seperation(sensed_boids);
+    fn set_perception(&mut self, perception: Perception) {
+        self.alignment_radius = perception.alignment_radius;
+        self.cohesion_radius = perception.cohesion_radius;
+        self.seperation_radius = perception.seperation_radius;
+        self.blind_angle = perception.blind_angle;
+        self.perception_size = perception
+            .alignment_radius
+            .max(perception.cohesion_radius)
+            .max(perception.seperation_radius);
+    }
 
-        self.edges(width, height); // wrap space into a torus
+    /// Advances `state` by one step, given what the boid sees around it, and
+    /// returns the new state.
+    pub fn step<R: Rng>(
+        &self,
+        rng: &mut R,
+        mut state: BoidState,
+        width: i32,
+        height: i32,
+        boundary: Boundary,
+        surroundings: &Surroundings,
+    ) -> BoidState {
+        let neighbours = surroundings.neighbours;
+        let alignment = self.align(&state, neighbours);
+        let cohesion = self.cohesion(&state, neighbours);
+        let seperation = self.seperation(&state, neighbours);
+        let flee = self.flee(&state, neighbours);
+        let chase = self.chase(&state, neighbours);
+        let avoidance = self.avoid(&state, surroundings.obstacles, surroundings.nearby);
+        let containment = if boundary.mode == BoundaryMode::Steer {
+            self.contain(&state, width, height, boundary.margin)
+        } else {
+            cgmath::Vector2::new(0.0, 0.0)
+        };
 
-        self.acceleration = self.acceleration + seperation + cohesion + alignment;
+        state.force = seperation
+            + cohesion
+            + alignment
+            + flee
+            + chase
+            + avoidance
+            + containment
+            + surroundings.steering
+            + surroundings.field;
 
-        self.position = self.position.add(self.velocity);
-        self.velocity = self.velocity.add(self.acceleration);
-        self.velocity = self.limit(&self.velocity, self.max_speed);
-        if self.velocity.magnitude() < self.max_speed * 0.25 {
-            self.velocity = self.setMag(self.max_speed * 0.25, &self.velocity);
+        state.position = state.position.add(state.velocity);
+        state.velocity = state.velocity.add(state.force);
+        state.velocity = limit(state.velocity, self.max_speed);
+        if state.velocity.magnitude() < self.max_speed * 0.25 {
+            state.velocity = set_magnitude(state.velocity, self.max_speed * 0.25);
         }
         //apply cohesion seperation and alignment forces
-    }
 
-    //instead of boids draining off the edges wrap space into a torus lmao
-    fn edges(&mut self, width: i32, height: i32) {
-        if self.position.x > (width + 11) as f32 {
-            self.position.x = -11.0;
-        } else if self.position.x < -11.0 {
-            self.position.x = (width + 11) as f32;
-        } // add some arbitrary amount so the triangles are popping in and out of existancel
+        // after moving so the position always lands inside the spatial index
+        self.edges(rng, &mut state, width, height, boundary.mode);
+        state
+    }
 
-        if self.position.y > (height + 11) as f32 {
-            self.position.y = -11.0;
-        } else if self.position.y < -11.0 {
-            self.position.y = (height + 11) as f32;
+    fn edges<R: Rng>(
+        &self,
+        rng: &mut R,
+        state: &mut BoidState,
+        width: i32,
+        height: i32,
+        mode: BoundaryMode,
+    ) {
+        let (width, height) = (width as f32, height as f32);
+        match mode {
+            BoundaryMode::Wrap => {
+                state.position.x = state.position.x.rem_euclid(width);
+                state.position.y = state.position.y.rem_euclid(height);
+            }
+            BoundaryMode::Bounce => {
+                if state.position.x < 0.0 {
+                    state.position.x = -state.position.x;
+                    state.velocity.x = state.velocity.x.abs();
+                } else if state.position.x >= width {
+                    state.position.x = 2.0 * width - state.position.x;
+                    state.velocity.x = -state.velocity.x.abs();
+                }
+                if state.position.y < 0.0 {
+                    state.position.y = -state.position.y;
+                    state.velocity.y = state.velocity.y.abs();
+                } else if state.position.y >= height {
+                    state.position.y = 2.0 * height - state.position.y;
+                    state.velocity.y = -state.velocity.y.abs();
+                }
+            }
+            BoundaryMode::Respawn => {
+                if state.position.x < 0.0
+                    || state.position.x >= width
+                    || state.position.y < 0.0
+                    || state.position.y >= height
+                {
+                    self.respawn(rng, state, width, height);
+                }
+            }
+            BoundaryMode::Steer => {}
         }
+
+        // keep clear of the far edges the spatial index treats as outside
+        state.position.x = state.position.x.max(0.0).min(width - 0.01);
+        state.position.y = state.position.y.max(0.0).min(height - 0.01);
     }
 
-    fn align(&mut self, boids: &Vec<(Boid, f32)>) -> cgmath::Vector2<f32> {
-        let mut steering = cgmath::Vector2::new(0.0, 0.0);
-        let mut total = 0;
-        for boid in boids {
-            if boid.0.index == self.index {
-                continue;
-            }
-            let distance = boid.1;
+    fn respawn<R: Rng>(&self, rng: &mut R, state: &mut BoidState, width: f32, height: f32) {
+        let along = rng.gen::<f32>();
+        let speed = self.max_speed * 0.5;
+        let (position, heading) = match rng.gen_range(0, 4) {
+            0 => ((along * width, 0.0), (0.0, 1.0)),
+            1 => ((along * width, height), (0.0, -1.0)),
+            2 => ((0.0, along * height), (1.0, 0.0)),
+            _ => ((width, along * height), (-1.0, 0.0)),
+        };
+        // spread the heading a little so respawned boids don't march in lines
+        let spread = (rng.gen::<f32>() - 0.5) * std::f32::consts::FRAC_PI_2;
+        let heading = cgmath::Basis2::from_angle(cgmath::Rad(spread))
+            .rotate_vector(cgmath::Vector2::new(heading.0, heading.1));
+        state.position = cgmath::Vector2::new(position.0, position.1);
+        state.velocity = heading * speed;
+    }
 
-            if distance < self.perception_size {
-                steering = steering.add(boid.0.velocity);
-                total += 1;
-            }
+    // turn back towards the middle once inside the margin, harder the deeper in
+    fn contain(
+        &self,
+        state: &BoidState,
+        width: i32,
+        height: i32,
+        margin: f32,
+    ) -> cgmath::Vector2<f32> {
+        let (width, height) = (width as f32, height as f32);
+        let position = state.position;
+        let mut push = cgmath::Vector2::new(0.0, 0.0);
+        if position.x < margin {
+            push.x += 1.0 - position.x / margin;
+        } else if position.x > width - margin {
+            push.x -= 1.0 - (width - position.x) / margin;
+        }
+        if position.y < margin {
+            push.y += 1.0 - position.y / margin;
+        } else if position.y > height - margin {
+            push.y -= 1.0 - (height - position.y) / margin;
         }
 
-        if total > 0 && steering != cgmath::Vector2::new(0.0, 0.0) {
-            steering /= total as f32;
-            steering = self.setMag(self.max_speed, &steering);
-            steering -= self.velocity;
-            steering = self.limit(&steering, self.alignment_force)
+        let mut steering = cgmath::Vector2::new(0.0, 0.0);
+        if push != cgmath::Vector2::new(0.0, 0.0) {
+            let urgency = push.magnitude().min(1.0);
+            steering = state.velocity + push * self.max_speed * 2.0;
+            steering = set_magnitude(steering, self.max_speed);
+            steering -= state.velocity;
+            steering = limit(steering, self.avoidance_force * urgency);
         }
 
-        return steering;
+        steering
     }
 
-    fn cohesion(&mut self, boids: &Vec<(Boid, f32)>) -> cgmath::Vector2<f32> {
-        let mut steering = cgmath::Vector2::new(0.0, 0.0);
-        let mut total = 0;
-        for other in boids {
-            let distance = other.1;
-            if distance < self.perception_size {
-                steering = steering + other.0.position;
-                total += 1;
-            }
+    pub fn agent(&self, state: &BoidState) -> steering::Agent {
+        steering::Agent {
+            position: state.position,
+            velocity: state.velocity,
+            max_speed: self.max_speed,
+            max_force: self.steering_force,
         }
-        if total > 0 && steering != cgmath::Vector2::new(0.0, 0.0) {
-            steering /= total as f32;
-            steering -= self.position;
-            steering = self.setMag(self.max_speed, &steering);
-            steering -= self.velocity;
-            steering = self.limit(&steering, self.cohesion_force);
+    }
+
+    /// Whether something at `offset` from this boid is outside the blind cone
+    /// behind it.
+    pub fn sees(&self, state: &BoidState, offset: cgmath::Vector2<f32>) -> bool {
+        let speed = state.velocity.magnitude();
+        let distance = offset.magnitude();
+        if self.blind_angle <= 0.0 || speed == 0.0 || distance == 0.0 {
+            return true;
         }
+        let cos = state.velocity.dot(offset) / (speed * distance);
+        let half_view = std::f32::consts::PI - self.blind_angle / 2.0;
+        cos.clamp(-1.0, 1.0).acos() <= half_view
+    }
 
-        return steering;
+    fn align(&self, state: &BoidState, neighbours: &[Neighbour]) -> cgmath::Vector2<f32> {
+        rules::align(
+            state.velocity,
+            neighbours,
+            self.alignment_radius,
+            self.max_speed,
+            self.alignment_force,
+        )
     }
 
-    fn seperation(&mut self, boids: &Vec<(Boid, f32)>) -> cgmath::Vector2<f32> {
-        let perception = 50.0 / 1.75;
+    fn cohesion(&self, state: &BoidState, neighbours: &[Neighbour]) -> cgmath::Vector2<f32> {
+        rules::cohesion(
+            state.velocity,
+            neighbours,
+            self.cohesion_radius,
+            self.max_speed,
+            self.cohesion_force,
+        )
+    }
+
+    fn seperation(&self, state: &BoidState, neighbours: &[Neighbour]) -> cgmath::Vector2<f32> {
+        rules::seperation(
+            state.velocity,
+            neighbours,
+            self.seperation_radius,
+            self.max_speed,
+            self.seperation_force,
+        )
+    }
+
+    fn flee(&self, state: &BoidState, neighbours: &[Neighbour]) -> cgmath::Vector2<f32> {
         let mut steering = cgmath::Vector2::new(0.0, 0.0);
         let mut total = 0;
-        for other in boids {
-            let distance = other.1;
-            if distance < perception {
-                let mut diff = self.position.clone();
-                diff -= other.0.position;
-                diff /= distance;
-                steering += diff;
+        for other in neighbours {
+            if other.interaction == Interaction::Flee && other.distance > 0.0 {
+                // closer predators push harder
+                steering -= other.offset / (other.distance * other.distance);
                 total += 1;
             }
         }
         if total > 0 && steering != cgmath::Vector2::new(0.0, 0.0) {
-            steering /= total as f32;
-            steering = self.setMag(self.max_speed, &steering);
-            steering -= self.velocity;
-            steering = self.limit(&steering, self.seperation_force);
+            steering = set_magnitude(steering, self.max_speed);
+            steering -= state.velocity;
+            steering = limit(steering, self.flee_force);
         }
 
-        return steering;
+        steering
     }
 
-    fn limit(&self, vec: &cgmath::Vector2<f32>, speed: f32) -> cgmath::Vector2<f32> {
-        if vec.magnitude() > speed {
-            return self.setMag(speed, vec);
-        } else {
-            return *vec;
+    fn chase(&self, state: &BoidState, neighbours: &[Neighbour]) -> cgmath::Vector2<f32> {
+        let mut steering = cgmath::Vector2::new(0.0, 0.0);
+        let target = neighbours
+            .iter()
+            .filter(|other| other.interaction == Interaction::Chase)
+            .min_by(|a, b| {
+                a.distance
+                    .partial_cmp(&b.distance)
+                    .unwrap_or(std::cmp::Ordering::Equal)
+            });
+
+        if let Some(prey) = target {
+            steering = set_magnitude(prey.offset, self.max_speed);
+            steering -= state.velocity;
+            steering = limit(steering, self.chase_force);
         }
+
+        steering
     }
 
-    fn setMag(&self, mag: f32, vec: &cgmath::Vector2<f32>) -> cgmath::Vector2<f32> {
-        let currentMag = vec.magnitude();
-        let mut newmag = cgmath::Vector2::new(0.0, 0.0);
+    // look ahead along the heading plus two shorter whiskers and turn away
+    // from the closest hit, harder the closer it is
+    fn avoid(
+        &self,
+        state: &BoidState,
+        obstacles: &[(usize, Obstacle)],
+        nearby: &[usize],
+    ) -> cgmath::Vector2<f32> {
+        let mut steering = cgmath::Vector2::new(0.0, 0.0);
+        let speed = state.velocity.magnitude();
+        if nearby.is_empty() || speed == 0.0 {
+            return steering;
+        }
+
+        let heading = state.velocity / speed;
+        let lookahead = self.perception_size;
+        let mut closest: Option<(f32, cgmath::Vector2<f32>)> = None;
+        for &(angle, reach) in &[(0.0, 1.0), (0.4, 0.5), (-0.4, 0.5)] {
+            let direction = cgmath::Basis2::from_angle(cgmath::Rad(angle)).rotate_vector(heading);
+            for &index in nearby {
+                let obstacle = &obstacles[index].1;
+                if let Some(hit) = obstacle.raycast(state.position, direction, lookahead * reach) {
+                    if !matches!(closest, Some(closest) if closest.0 <= hit.0) {
+                        closest = Some(hit);
+                    }
+                }
+            }
+        }
 
-        if currentMag != 0.0 {
-            newmag = (vec * mag) / currentMag;
+        if let Some((distance, normal)) = closest {
+            steering = heading + normal * 2.0;
+            steering = set_magnitude(steering, self.max_speed);
+            steering -= state.velocity;
+            let urgency = 1.0 - distance / lookahead;
+            steering = limit(steering, self.avoidance_force * urgency);
         }
 
-        return newmag;
+        steering
     }
 }
 
+// GL resources for drawing the flock, absent when running headless
+struct FlockRenderer {
+    triangle: Triangle,
+    line: Rectangle,
+}
+
+// Scratch space reused between boids so a step doesn't allocate per boid.
+#[derive(Default)]
+struct Scratch {
+    selected: Vec<usize>,
+    nearest: Vec<(f32, usize)>,
+    neighbours: Vec<Neighbour>,
+    nearby: Vec<usize>,
+}
+
+/// What a boid sees around it during a step, gathered by the flock.
+pub struct Surroundings<'a> {
+    /// The visible boids in range.
+    pub neighbours: &'a [Neighbour],
+    pub obstacles: &'a [(usize, Obstacle)],
+    /// Indexes of the obstacles close enough to matter.
+    pub nearby: &'a [usize],
+    /// Weighted sum of the flock's steering behaviours for this boid.
+    pub steering: cgmath::Vector2<f32>,
+    /// Force of the flock's vector fields where the boid is.
+    pub field: cgmath::Vector2<f32>,
+}
+
+// Everything a boid reads during a step, borrowed from the flock field by
+// field. Unlike the flock, which holds WebGL handles, this can be shared
+// between threads.
+struct Step<'a> {
+    boids: &'a [Boid],
+    current: &'a BoidBuffer,
+    quadtree: &'a Quadtree,
+    interactions: &'a [Vec<Interaction>],
+    behaviours: &'a [(usize, Behaviour, f32)],
+    fields: &'a [(usize, VectorField, f32)],
+    obstacles: &'a [(usize, Obstacle)],
+    obstacle_tree: &'a LooseQuadtree,
+    boundary: Boundary,
+    neighbour_mode: NeighbourMode,
+    neighbour_count: usize,
+    dimensions: (u32, u32),
+    seed: u64,
+    step: u64,
+}
+
 pub struct Flock {
     dimensions: (u32, u32),
     aspect: f32,
     boids: Vec<Boid>,
-    triangle: Triangle,
+    // state of the last step, read while the next one is written into `next`
+    current: BoidBuffer,
+    next: BoidBuffer,
+    scratch: Scratch,
+    // seeds spawning and every boid's per step random numbers
+    seed: u64,
+    steps: u64,
+    rng: StdRng,
+    // step boids on the rayon thread pool
+    parallel: bool,
+    species: Vec<Species>,
+    // interactions[a][b] is how species a reacts to species b
+    interactions: Vec<Vec<Interaction>>,
+    perception: Perception,
+    boundary: Boundary,
+    neighbour_mode: NeighbourMode,
+    // how many boids count as neighbours in topological mode
+    neighbour_count: usize,
+    behaviours: Vec<(usize, Behaviour, f32)>,
+    next_behaviour_id: usize,
+    fields: Vec<(usize, VectorField, f32)>,
+    next_field_id: usize,
+    // draw an arrow grid of the summed fields
+    show_fields: bool,
+    obstacles: Vec<(usize, Obstacle)>,
+    // indexes into obstacles, rebuilt whenever an obstacle is added or removed
+    obstacle_tree: LooseQuadtree,
+    next_obstacle_id: usize,
     quadtree: Quadtree,
-    line: Rectangle,
+    trails: Trails,
+    // share of a trail's opacity lost per step of age
+    trail_fade: f32,
+    // radius of a boid for collision avoidance, 0 when it's off
+    agent_radius: f32,
+    // steps ahead collision avoidance looks for collisions
+    time_horizon: f32,
+    renderer: Option<FlockRenderer>,
     encoder: ScreenSpaceEncoder,
     count: u32,
 }
 
 impl Flock {
     pub fn new(gl: &GL, width: u32, height: u32) -> Self {
+        let boidshape = [0.0, 0.5, 0.34, -0.5, -0.34, -0.5];
+        let mut flock = Flock::headless(width, height, rand::thread_rng().gen());
+        flock.renderer = Some(FlockRenderer {
+            triangle: Triangle::new(gl, boidshape),
+            line: Rectangle::new(gl),
+        });
+        flock
+    }
+
+    /// A flock without any GL resources, for stepping outside the browser.
+    /// The same seed always gives the same flock and the same steps.
+    pub fn headless(width: u32, height: u32, seed: u64) -> Self {
         let encoder = ScreenSpaceEncoder {
             dimensions: (width, height),
         };
 
-        let boidshape = [0.0, 0.5, 0.34, -0.5, -0.34, -0.5];
-        let mut rng = rand::thread_rng();
-        let mut boids = Vec::<Boid>::new();
-        let mut qt = Quadtree::new(
+        let qt = Quadtree::new(
             2,
             Rect {
                 x: 0.0,
@@ -178,58 +536,705 @@ impl Flock {
             },
         );
 
-        for index in 0..300 {
-            boids.push(Boid {
-                position: cgmath::Vector2::new(
-                    rng.gen::<f32>() * encoder.dimensions.0 as f32,
-                    rng.gen::<f32>() * encoder.dimensions.1 as f32,
-                ),
-                velocity: cgmath::Vector2::new(
-                    (rng.gen::<f32>() * 2.0) - 1.0,
-                    (rng.gen::<f32>() * 2.0) - 1.0,
-                ),
-                acceleration: cgmath::Vector2::new(0.0, 0.0),
-                alignment_force: 0.4,
-                cohesion_force: 0.2,
-                seperation_force: 0.4,
-                perception_size: 75.0 / 2.0,
-                max_speed: 7.0 / 2.0,
-                index,
-            });
-
-            //TODO vectorlib
-            let _ = qt.insert(boids[index].position, index);
-        }
-
-        Self {
+        let mut flock = Self {
             dimensions: (width, height),
             aspect: width as f32 / height as f32,
-            triangle: Triangle::new(&gl, boidshape),
-            boids,
+            boids: Vec::new(),
+            current: BoidBuffer::default(),
+            next: BoidBuffer::default(),
+            scratch: Scratch::default(),
+            seed,
+            steps: 0,
+            rng: StdRng::seed_from_u64(seed),
+            parallel: thread_pool::is_ready(),
+            species: vec![Species {
+                color: [0.37, 0.22, 0.40, 1.0],
+                size: 0.05,
+                max_speed: 7.0 / 2.0,
+            }],
+            interactions: vec![vec![Interaction::Flock]],
+            perception: Perception {
+                alignment_radius: 75.0 / 2.0,
+                cohesion_radius: 75.0 / 2.0,
+                seperation_radius: 50.0 / 1.75,
+                blind_angle: 0.0,
+            },
+            boundary: Boundary {
+                mode: BoundaryMode::Wrap,
+                margin: 50.0,
+            },
+            neighbour_mode: NeighbourMode::Metric,
+            neighbour_count: 7,
+            behaviours: Vec::new(),
+            next_behaviour_id: 0,
+            fields: Vec::new(),
+            next_field_id: 0,
+            show_fields: false,
+            obstacles: Vec::new(),
+            obstacle_tree: LooseQuadtree::new(
+                4,
+                5,
+                Rect {
+                    x: 0.0,
+                    y: 0.0,
+                    width: width as f32,
+                    height: height as f32,
+                },
+            ),
+            next_obstacle_id: 0,
             quadtree: qt,
-            line: Rectangle::new(&gl),
+            trails: Trails::default(),
+            trail_fade: 0.1,
+            agent_radius: 0.0,
+            time_horizon: 10.0,
+            renderer: None,
             encoder,
             count: 0,
+        };
+        flock.resize(300);
+        flock
+    }
+
+    /// Registers a new species and returns its id. It flocks with itself and
+    /// ignores every other species until told otherwise.
+    pub fn add_species(&mut self, species: Species) -> usize {
+        for row in self.interactions.iter_mut() {
+            row.push(Interaction::Ignore);
+        }
+        self.species.push(species);
+        let id = self.species.len() - 1;
+        let mut row = vec![Interaction::Ignore; self.species.len()];
+        row[id] = Interaction::Flock;
+        self.interactions.push(row);
+        id
+    }
+
+    /// Sets how boids of species `a` react to boids of species `b`.
+    pub fn set_interaction(&mut self, a: usize, b: usize, interaction: Interaction) {
+        if a < self.species.len() && b < self.species.len() {
+            self.interactions[a][b] = interaction;
+        }
+    }
+
+    /// Moves the first `count` boids into `species`.
+    pub fn assign_species(&mut self, species: usize, count: usize) {
+        if species >= self.species.len() {
+            return;
+        }
+        for boid in self.boids.iter_mut().take(count) {
+            boid.species = species;
+            boid.max_speed = self.species[species].max_speed;
+        }
+    }
+
+    /// A few fast hawks hunting the rest of the flock. Replaces whatever
+    /// species the flock had, so calling it again just re-deals the hawks.
+    pub fn hawks_and_starlings(&mut self, hawks: usize) {
+        let starlings = 0;
+        self.species.truncate(1);
+        self.interactions = vec![vec![Interaction::Flock]];
+        self.species[starlings] = Species {
+            color: [0.37, 0.22, 0.40, 1.0],
+            size: 0.04,
+            max_speed: 7.0 / 2.0,
+        };
+        self.assign_species(starlings, self.boids.len());
+        let hawk = self.add_species(Species {
+            color: [0.85, 0.45, 0.25, 1.0],
+            size: 0.08,
+            max_speed: 4.5,
+        });
+        self.set_interaction(starlings, hawk, Interaction::Flee);
+        self.set_interaction(hawk, starlings, Interaction::Chase);
+        // hawks hunt alone
+        self.set_interaction(hawk, hawk, Interaction::Ignore);
+        self.assign_species(hawk, hawks);
+    }
+
+    /// Adds a steering behaviour applied to every boid with the given weight
+    /// and returns the id used to remove it again.
+    pub fn add_behaviour(&mut self, behaviour: Behaviour, weight: f32) -> usize {
+        let id = self.next_behaviour_id;
+        self.next_behaviour_id += 1;
+        self.behaviours.push((id, behaviour, weight));
+        id
+    }
+
+    pub fn remove_behaviour(&mut self, id: usize) -> bool {
+        let count = self.behaviours.len();
+        self.behaviours.retain(|behaviour| behaviour.0 != id);
+        self.behaviours.len() != count
+    }
+
+    pub fn clear_behaviours(&mut self) {
+        self.behaviours.clear();
+    }
+
+    /// Adds a vector field whose force, scaled by `weight`, acts on every boid.
+    /// Returns the id used to paint or remove it.
+    pub fn add_field(&mut self, field: VectorField, weight: f32) -> usize {
+        let id = self.next_field_id;
+        self.next_field_id += 1;
+        self.fields.push((id, field, weight));
+        id
+    }
+
+    pub fn remove_field(&mut self, id: usize) -> bool {
+        let count = self.fields.len();
+        self.fields.retain(|field| field.0 != id);
+        self.fields.len() != count
+    }
+
+    pub fn clear_fields(&mut self) {
+        self.fields.clear();
+    }
+
+    /// Paints onto a grid field, returns false when `id` isn't a grid.
+    pub fn paint_field(
+        &mut self,
+        id: usize,
+        position: cgmath::Vector2<f32>,
+        vector: cgmath::Vector2<f32>,
+        radius: f32,
+    ) -> bool {
+        match self.fields.iter_mut().find(|field| field.0 == id) {
+            Some((_, field, _)) => field.paint(position, vector, radius),
+            None => false,
+        }
+    }
+
+    pub fn show_fields(&mut self, show: bool) {
+        self.show_fields = show;
+    }
+
+    // weighted sum of every field at `position`
+    fn field(
+        fields: &[(usize, VectorField, f32)],
+        position: cgmath::Vector2<f32>,
+        step: u64,
+    ) -> cgmath::Vector2<f32> {
+        fields
+            .iter()
+            .fold(cgmath::Vector2::new(0.0, 0.0), |sum, (_, field, weight)| {
+                sum + field.sample(position, step as f32) * *weight
+            })
+    }
+
+    // weighted sum of every behaviour, `previous` is the state before this step
+    fn steering<R: Rng>(
+        behaviours: &[(usize, Behaviour, f32)],
+        boid: &Boid,
+        state: &mut BoidState,
+        previous: &BoidBuffer,
+        rng: &mut R,
+    ) -> cgmath::Vector2<f32> {
+        let agent = boid.agent(state);
+        let zero = cgmath::Vector2::new(0.0, 0.0);
+        let mut force = zero;
+        for (_, behaviour, weight) in behaviours {
+            let target = |index: usize| {
+                if index != boid.index && index < previous.len() {
+                    Some((previous.positions[index], previous.velocities[index]))
+                } else {
+                    None
+                }
+            };
+            force += match behaviour {
+                Behaviour::Seek(target) => steering::seek(&agent, *target),
+                Behaviour::Flee(threat) => steering::flee(&agent, *threat),
+                Behaviour::Arrive {
+                    target,
+                    slowing_radius,
+                } => steering::arrive(&agent, *target, *slowing_radius),
+                Behaviour::Wander(params) => {
+                    let sample = rng.gen::<f32>() * 2.0 - 1.0;
+                    steering::wander(&agent, &mut state.wander_angle, sample, params)
+                }
+                Behaviour::Pursue(index) => target(*index).map_or(zero, |(position, velocity)| {
+                    steering::pursue(&agent, position, velocity)
+                }),
+                Behaviour::Evade(index) => target(*index).map_or(zero, |(position, velocity)| {
+                    steering::evade(&agent, position, velocity)
+                }),
+                Behaviour::FollowPath(path) => steering::follow_path(&agent, path),
+                Behaviour::FollowLeader { leader, distance } => {
+                    target(*leader).map_or(zero, |(position, velocity)| {
+                        steering::follow_leader(&agent, position, velocity, *distance)
+                    })
+                }
+            } * *weight;
+        }
+        force
+    }
+
+    /// Adds a static obstacle and returns the id used to remove it again.
+    pub fn add_obstacle(&mut self, obstacle: Obstacle) -> usize {
+        let id = self.next_obstacle_id;
+        self.next_obstacle_id += 1;
+        self.obstacles.push((id, obstacle.normalized()));
+        self.rebuild_obstacle_tree();
+        id
+    }
+
+    pub fn remove_obstacle(&mut self, id: usize) -> bool {
+        let count = self.obstacles.len();
+        self.obstacles.retain(|obstacle| obstacle.0 != id);
+        self.rebuild_obstacle_tree();
+        self.obstacles.len() != count
+    }
+
+    pub fn clear_obstacles(&mut self) {
+        self.obstacles.clear();
+        self.obstacle_tree.clear();
+    }
+
+    fn rebuild_obstacle_tree(&mut self) {
+        self.obstacle_tree = LooseQuadtree::new(
+            4,
+            5,
+            Rect {
+                x: 0.0,
+                y: 0.0,
+                width: self.dimensions.0 as f32,
+                height: self.dimensions.1 as f32,
+            },
+        );
+        for (index, obstacle) in self.obstacles.iter().enumerate() {
+            self.obstacle_tree.insert(obstacle.1.bounds(), index);
+        }
+    }
+
+    pub fn set_perception(&mut self, mut perception: Perception) {
+        perception.blind_angle = perception
+            .blind_angle
+            .clamp(0.0, std::f32::consts::PI * 2.0);
+        self.perception = perception;
+        for boid in self.boids.iter_mut() {
+            boid.set_perception(perception);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.boids.len()
+    }
+
+    /// Adds a boid and returns its index. Indices are positions in the flock
+    /// and shift when boids are removed.
+    pub fn spawn(
+        &mut self,
+        position: cgmath::Vector2<f32>,
+        velocity: cgmath::Vector2<f32>,
+        species: usize,
+    ) -> usize {
+        let index = self.boids.len();
+        let mut boid = Boid::new(index);
+        boid.set_perception(self.perception);
+        if species < self.species.len() {
+            boid.species = species;
+            boid.max_speed = self.species[species].max_speed;
+        }
+        let state = BoidState::new(position, velocity);
+        self.boids.push(boid);
+        self.current.push(state);
+        self.next.push(state);
+        self.trails.push_boid();
+        self.quadtree.insert(position, index);
+        index
+    }
+
+    /// Removes every boid within `radius` of the point and returns how many.
+    pub fn remove_in_radius(&mut self, position: cgmath::Vector2<f32>, radius: f32) -> usize {
+        let keep: Vec<bool> = self
+            .current
+            .positions
+            .iter()
+            .map(|other| other.distance(position) > radius)
+            .collect();
+        let count = self.boids.len();
+        let mut flags = keep.iter();
+        self.boids.retain(|_| *flags.next().unwrap());
+        self.current.retain(&keep);
+        self.next.retain(&keep);
+        self.trails.retain(&keep);
+        let removed = count - self.boids.len();
+        if removed > 0 {
+            self.retarget_behaviours(&keep);
+            self.reindex();
+        }
+        removed
+    }
+
+    /// Spawns boids at random or drops the newest ones until there are `count`.
+    pub fn resize(&mut self, count: usize) {
+        if count < self.boids.len() {
+            let keep: Vec<bool> = (0..self.boids.len()).map(|index| index < count).collect();
+            self.boids.truncate(count);
+            self.current.truncate(count);
+            self.next.truncate(count);
+            self.trails.truncate(count);
+            self.retarget_behaviours(&keep);
+            self.reindex();
+            return;
+        }
+
+        while self.boids.len() < count {
+            let position = cgmath::Vector2::new(
+                self.rng.gen::<f32>() * self.dimensions.0 as f32,
+                self.rng.gen::<f32>() * self.dimensions.1 as f32,
+            );
+            let velocity = cgmath::Vector2::new(
+                (self.rng.gen::<f32>() * 2.0) - 1.0,
+                (self.rng.gen::<f32>() * 2.0) - 1.0,
+            );
+            self.spawn(position, velocity, 0);
         }
     }
 
-    fn wrapped_distance(
-        vec1: cgmath::Vector2<f32>,
-        vec2: cgmath::Vector2<f32>,
+    // Behaviours aimed at a boid follow it to its new index once the boids
+    // flagged in `keep` are kept, and are dropped when their boid is gone.
+    fn retarget_behaviours(&mut self, keep: &[bool]) {
+        let mut remap = Vec::with_capacity(keep.len());
+        let mut next = 0;
+        for &kept in keep {
+            remap.push(if kept { Some(next) } else { None });
+            next += kept as usize;
+        }
+        self.behaviours = std::mem::take(&mut self.behaviours)
+            .into_iter()
+            .filter_map(|(id, mut behaviour, weight)| {
+                if let Some(index) = behaviour.target_mut() {
+                    *index = remap.get(*index).copied().flatten()?;
+                }
+                Some((id, behaviour, weight))
+            })
+            .collect();
+    }
+
+    // boid indices double as quadtree entries, so both are rebuilt together
+    fn reindex(&mut self) {
+        let mut quadtree = Quadtree::new(
+            2,
+            Rect {
+                x: 0.0,
+                y: 0.0,
+                width: self.dimensions.0 as f32,
+                height: self.dimensions.1 as f32,
+            },
+        );
+        for (index, boid) in self.boids.iter_mut().enumerate() {
+            boid.index = index;
+            quadtree.insert(self.current.positions[index], index);
+        }
+        self.quadtree = quadtree;
+    }
+
+    /// Draws the last `length` positions of every boid behind it, losing
+    /// `fade` of the opacity with every step of age. A length of 0 turns
+    /// trails off.
+    pub fn set_trails(&mut self, length: usize, fade: f32) {
+        if length != self.trails.length() {
+            self.trails.set_length(length);
+        }
+        self.trail_fade = fade.clamp(0.0, 1.0);
+    }
+
+    /// Steps boids on the rayon thread pool. Results are identical either way.
+    /// Ignored without the `parallel` feature or until the thread pool is
+    /// ready, which on the web means started from JS.
+    pub fn set_parallel(&mut self, parallel: bool) {
+        self.parallel = parallel && thread_pool::is_ready();
+    }
+
+    /// Keeps boids of `radius` from overlapping by picking velocities that
+    /// stay clear of each other for `time_horizon` steps, then pushing apart
+    /// any that still touch. A radius of 0 turns it off.
+    pub fn set_collision_avoidance(&mut self, radius: f32, time_horizon: f32) {
+        self.agent_radius = radius.max(0.0);
+        self.time_horizon = time_horizon.max(1.0);
+    }
+
+    pub fn set_neighbour_mode(&mut self, mode: NeighbourMode) {
+        self.neighbour_mode = mode;
+    }
+
+    /// Neighbours per boid in `Topological` mode.
+    pub fn set_neighbour_count(&mut self, count: usize) {
+        self.neighbour_count = count;
+    }
+
+    pub fn set_boundary_mode(&mut self, mode: BoundaryMode) {
+        self.boundary.mode = mode;
+    }
+
+    pub fn set_boundary_margin(&mut self, margin: f32) {
+        self.boundary.margin = margin.max(1.0);
+    }
+
+    // the copy of `to` on the torus closest to `from`
+    fn nearest_image(
+        from: cgmath::Vector2<f32>,
+        to: cgmath::Vector2<f32>,
+        width: u32,
+        height: u32,
+    ) -> cgmath::Vector2<f32> {
+        let (width, height) = (width as f32, height as f32);
+        let mut image = to;
+        if image.x - from.x > width / 2.0 {
+            image.x -= width;
+        } else if from.x - image.x > width / 2.0 {
+            image.x += width;
+        }
+        if image.y - from.y > height / 2.0 {
+            image.y -= height;
+        } else if from.y - image.y > height / 2.0 {
+            image.y += height;
+        }
+        image
+    }
+
+    // `position` first, then its copies across any seam closer than `reach`
+    fn wrapped_images(
+        position: cgmath::Vector2<f32>,
+        reach: f32,
         width: u32,
         height: u32,
-    ) -> f32 {
-        let mut dx = (vec1.x - vec2.x).abs();
-        let mut dy = (vec1.y - vec2.y).abs();
+    ) -> impl Iterator<Item = cgmath::Vector2<f32>> {
+        let wrap = move |value: f32, size: f32| {
+            if value < reach {
+                Some(value + size)
+            } else if value > size - reach {
+                Some(value - size)
+            } else {
+                None
+            }
+        };
+        let xs = std::iter::once(position.x).chain(wrap(position.x, width as f32));
+        let ys = std::iter::once(position.y).chain(wrap(position.y, height as f32));
+        xs.flat_map(move |x| ys.clone().map(move |y| cgmath::Vector2::new(x, y)))
+    }
+
+    /// Measures how the flock moved in the last step. Distances take the short
+    /// way around the world in `Wrap` mode.
+    pub fn metrics(&self) -> FlockMetrics {
+        let positions = &self.current.positions;
+        let wrap = self.boundary.mode == BoundaryMode::Wrap;
+        let longest = self.dimensions.0.max(self.dimensions.1) as f32;
+        let mut components = metrics::Components::new(self.boids.len());
+        let (mut nearest_total, mut nearest_count) = (0.0, 0);
+        let mut found = Vec::new();
+        for (index, boid) in self.boids.iter().enumerate() {
+            let position = positions[index];
+            let distance = |other: usize| {
+                let mut other = positions[other];
+                if wrap {
+                    other =
+                        Flock::nearest_image(position, other, self.dimensions.0, self.dimensions.1);
+                }
+                other.distance(position)
+            };
+
+            // search outwards from the perception radius until someone turns up
+            let mut radius = boid.perception_size;
+            loop {
+                found.clear();
+                Flock::query_wrapped(
+                    &self.quadtree,
+                    wrap,
+                    self.dimensions,
+                    position,
+                    radius,
+                    &mut found,
+                );
+                if radius == boid.perception_size {
+                    for &other in &found {
+                        components.join(index, other);
+                    }
+                }
+                let nearest = found
+                    .iter()
+                    .filter(|&&other| other != index)
+                    .map(|&other| distance(other))
+                    .fold(None, |nearest: Option<f32>, distance| {
+                        Some(nearest.map_or(distance, |nearest| nearest.min(distance)))
+                    });
+                if let Some(nearest) = nearest {
+                    nearest_total += nearest;
+                    nearest_count += 1;
+                    break;
+                }
+                if radius > longest {
+                    break;
+                }
+                radius *= 2.0;
+            }
+        }
+
+        FlockMetrics {
+            polarization: metrics::polarization(&self.current.velocities),
+            angular_momentum: metrics::angular_momentum(positions, &self.current.velocities),
+            average_speed: metrics::average_speed(&self.current.velocities),
+            nearest_neighbour: nearest_total / nearest_count.max(1) as f32,
+            clusters: components.count() as u32,
+        }
+    }
+
+    // boids within `radius` of `position`, looking across the seam on a torus.
+    // Sorted so sums over them don't depend on the quadtree's layout.
+    fn query_wrapped(
+        quadtree: &Quadtree,
+        wrap: bool,
+        dimensions: (u32, u32),
+        position: cgmath::Vector2<f32>,
+        radius: f32,
+        found: &mut Vec<usize>,
+    ) {
+        if wrap {
+            for image in Flock::wrapped_images(position, radius, dimensions.0, dimensions.1) {
+                quadtree.query_into((image.x, image.y, radius), found);
+            }
+            found.sort_unstable();
+            found.dedup();
+        } else {
+            quadtree.query_into((position.x, position.y, radius), found);
+            found.sort_unstable();
+        }
+    }
+
+    // Replaces the velocities just chosen with the closest ones that won't
+    // collide, so the next step moves boids clear of each other, then
+    // separates boids that overlap anyway.
+    fn avoid_collisions(&mut self) {
+        let (width, height) = self.dimensions;
+        let wrap = self.boundary.mode == BoundaryMode::Wrap;
+        let radius = self.agent_radius;
+        let mut found = Vec::new();
+        let mut others = Vec::new();
+        let mut lines = Vec::new();
+
+        for index in 0..self.boids.len() {
+            let position = self.next.positions[index];
+            let max_speed = self.boids[index].max_speed;
+            // far enough to meet anyone within the horizon, plus how far
+            // boids moved since the quadtree was built
+            let reach = 2.0 * radius + 2.0 * max_speed * (self.time_horizon + 1.0);
+            found.clear();
+            Flock::query_wrapped(
+                &self.quadtree,
+                wrap,
+                self.dimensions,
+                self.current.positions[index],
+                reach,
+                &mut found,
+            );
+
+            others.clear();
+            for &other in found.iter() {
+                if other == index {
+                    continue;
+                }
+                let mut other_position = self.next.positions[other];
+                if wrap {
+                    other_position = Flock::nearest_image(position, other_position, width, height);
+                }
+                if (other_position - position).magnitude() > reach {
+                    continue;
+                }
+                others.push(OrcaAgent {
+                    position: other_position,
+                    velocity: self.current.velocities[other],
+                    radius,
+                    max_speed: self.boids[other].max_speed,
+                });
+            }
 
-        if dx > width as f32 {
-            dx = width as f32 - dx;
+            let agent = OrcaAgent {
+                position,
+                velocity: self.current.velocities[index],
+                radius,
+                max_speed,
+            };
+            self.next.velocities[index] = orca::safe_velocity(
+                &agent,
+                self.next.velocities[index],
+                &others,
+                self.time_horizon,
+                &mut lines,
+            );
         }
-        if dy > height as f32 {
-            dy = height as f32 - dy;
+
+        // boids close enough to be pushed into each other, found around where
+        // they are going since a respawned boid isn't near where it was
+        let mut going = Quadtree::new(
+            2,
+            Rect {
+                x: 0.0,
+                y: 0.0,
+                width: width as f32,
+                height: height as f32,
+            },
+        );
+        for (index, position) in self.next.positions.iter().enumerate() {
+            going.insert(*position, index);
+        }
+        let mut pairs = Vec::new();
+        for (index, &position) in self.next.positions.iter().enumerate() {
+            found.clear();
+            Flock::query_wrapped(
+                &going,
+                wrap,
+                self.dimensions,
+                position,
+                4.0 * radius,
+                &mut found,
+            );
+            pairs.extend(
+                found
+                    .iter()
+                    .filter(|&&other| other > index)
+                    .map(|&other| (index, other)),
+            );
         }
 
-        return (dx.powi(2) + dy.powi(2)).sqrt();
+        let mut agents: Vec<OrcaAgent> = self
+            .next
+            .positions
+            .iter()
+            .map(|&position| OrcaAgent {
+                position,
+                velocity: cgmath::Vector2::new(0.0, 0.0),
+                radius,
+                max_speed: 0.0,
+            })
+            .collect();
+        // kept inside while being pushed, so a boid against an edge moves
+        // the other one instead of being pushed back into it
+        orca::separate(
+            &mut agents,
+            &pairs,
+            |a, b| {
+                if wrap {
+                    Flock::nearest_image(a, b, width, height) - a
+                } else {
+                    b - a
+                }
+            },
+            |position| self.keep_inside(position),
+        );
+        for (index, agent) in agents.iter().enumerate() {
+            self.next.positions[index] = self.keep_inside(agent.position);
+        }
+    }
+
+    // back inside the world after being pushed, the same way boids stay in
+    fn keep_inside(&self, mut position: cgmath::Vector2<f32>) -> cgmath::Vector2<f32> {
+        let (width, height) = (self.dimensions.0 as f32, self.dimensions.1 as f32);
+        if self.boundary.mode == BoundaryMode::Wrap {
+            position.x = position.x.rem_euclid(width);
+            position.y = position.y.rem_euclid(height);
+        }
+        position.x = position.x.max(0.0).min(width - 0.01);
+        position.y = position.y.max(0.0).min(height - 0.01);
+        position
     }
 
     fn getLocalBoids(&self, circle: (f32, f32, f32)) {
@@ -248,8 +1253,12 @@ impl Flock {
         self.encoder.updateDimensions(width as u32, height as u32);
         self.count = (self.count + 1) % 101;
         self.aspect = width as f32 / height as f32;
+        let resized = self.dimensions != (width as u32, height as u32);
         self.dimensions = (width as u32, height as u32);
         self.quadtree.set_dimensions(width as f32, height as f32);
+        if resized {
+            self.rebuild_obstacle_tree();
+        }
         let mut newquadtree = Quadtree::new(
             2,
             Rect {
@@ -260,35 +1269,99 @@ impl Flock {
             },
         );
         //self.quadtree.reset();
+        self.trails.record(&self.current.positions);
+
+        let step = Step {
+            boids: &self.boids,
+            current: &self.current,
+            quadtree: &self.quadtree,
+            interactions: &self.interactions,
+            behaviours: &self.behaviours,
+            fields: &self.fields,
+            obstacles: &self.obstacles,
+            obstacle_tree: &self.obstacle_tree,
+            boundary: self.boundary,
+            dimensions: self.dimensions,
+            seed: self.seed,
+            neighbour_mode: self.neighbour_mode,
+            neighbour_count: self.neighbour_count,
+            step: self.steps,
+        };
+        if self.parallel {
+            step.run_parallel(&mut self.next);
+        } else {
+            step.run(&mut self.next, &mut self.scratch);
+        }
+        if self.agent_radius > 0.0 {
+            self.avoid_collisions();
+        }
+
+        for (index, position) in self.next.positions.iter().enumerate() {
+            newquadtree.insert(*position, index);
+        }
+        std::mem::swap(&mut self.current, &mut self.next);
+        self.quadtree = newquadtree;
+        self.steps += 1;
+    }
 
-        //got a feeling this needs to be in the loop, p sure it causes ghost boids or something when it isnt
-        let test = self.boids.clone();
-        for (pos, boid) in self.boids.iter_mut().enumerate() {
-            let mut sensed: Vec<(Boid, f32)> = Vec::new();
-
-            let selected =
-                self.quadtree
-                    .query((boid.position.x, boid.position.y, boid.perception_size));
-
-            for i in selected {
-                if boid.index != test[i].index {
-                    sensed.push((
-                        test[i],
-                        crate::Flock::wrapped_distance(
-                            boid.position,
-                            test[i].position,
-                            self.dimensions.0,
-                            self.dimensions.1,
-                        ),
-                    ))
+    // an arrow on a regular grid pointing along the summed fields, bigger where
+    // they're stronger
+    fn field_instances(&self) -> Vec<Instance> {
+        let mut instances = Vec::new();
+        let mut y = FIELD_ARROW_SPACING / 2.0;
+        while y < self.dimensions.1 as f32 {
+            let mut x = FIELD_ARROW_SPACING / 2.0;
+            while x < self.dimensions.0 as f32 {
+                let force = Flock::field(&self.fields, cgmath::Vector2::new(x, y), self.steps);
+                if force.magnitude2() > 0.0 {
+                    let (cx, cy) = self.encoder.encode(x, y);
+                    let size = (force.magnitude() * 0.1).clamp(0.01, 0.04);
+                    instances.push(Instance {
+                        x: cx,
+                        y: cy,
+                        width: size / 2.0,
+                        height: size,
+                        angle: force.y.atan2(force.x) - std::f32::consts::FRAC_PI_2,
+                        color: [0.3, 0.3, 0.3, 1.0],
+                    });
                 }
+                x += FIELD_ARROW_SPACING;
             }
+            y += FIELD_ARROW_SPACING;
+        }
+        instances
+    }
 
-            boid.update(width, height, &sensed);
-
-            newquadtree.insert(boid.position, pos);
+    // one thin rectangle per trail segment, fading with age
+    fn trail_instances(&self) -> Vec<Instance> {
+        let (width, height) = (self.dimensions.0 as f32, self.dimensions.1 as f32);
+        let mut instances = Vec::new();
+        for (index, boid) in self.boids.iter().enumerate() {
+            let color = self.species[boid.species].color;
+            let mut alpha = color[3];
+            let mut newer = self.current.positions[index];
+            for older in self.trails.points(index) {
+                alpha *= 1.0 - self.trail_fade;
+                let offset = newer - older;
+                // wrapping or respawning teleports, don't streak across the world
+                if offset.x.abs() > width / 2.0 || offset.y.abs() > height / 2.0 {
+                    break;
+                }
+                let start = self.encoder.encode(older.x, older.y);
+                let end = self.encoder.encode(newer.x, newer.y);
+                let (dx, dy) = (end.0 - start.0, end.1 - start.1);
+                instances.push(Instance {
+                    x: start.0,
+                    y: start.1,
+                    width: (dx * dx + dy * dy).sqrt(),
+                    height: TRAIL_WIDTH,
+                    angle: dy.atan2(dx),
+                    color: [color[0], color[1], color[2], alpha],
+                });
+                newer = older;
+            }
         }
-        self.quadtree = newquadtree;
+        instances
     }
 
     pub fn render(&self, gl: &GL) {
@@ -297,28 +1370,61 @@ impl Flock {
             self.dimensions.1 as f32 / 2.0,
             100.0,
         ));*/
-        self.quadtree.renderroot(&gl, &self.line, self.encoder);
+        let renderer = match &self.renderer {
+            Some(renderer) => renderer,
+            None => return,
+        };
+        self.quadtree.renderroot(gl, &renderer.line, self.encoder);
+
+        let mut obstacles = Vec::<Instance>::new();
+        for (_, obstacle) in &self.obstacles {
+            obstacles.extend(obstacle.instances(&self.encoder));
+        }
+        renderer.line.render_instances(gl, obstacles);
+
+        if self.show_fields && !self.fields.is_empty() {
+            renderer
+                .triangle
+                .render_instances(gl, self.field_instances());
+        }
+
+        if self.trails.length() > 0 {
+            gl.enable(GL::BLEND);
+            gl.blend_func(GL::SRC_ALPHA, GL::ONE_MINUS_SRC_ALPHA);
+            renderer.line.render_instances(gl, self.trail_instances());
+            gl.disable(GL::BLEND);
+        }
 
-        let mut color = [1.0, 1.0, 1.0, 1.0];
         let mut instances = Vec::<Instance>::with_capacity(self.boids.len());
         for (index, boid) in self.boids.iter().enumerate() {
-            let ang = boid.velocity.y.atan2(boid.velocity.x);
-
-            //if selected.iter().any(|&i| i == index) {
-            //    color = [0.0, 1.0, 0.0, 1.0];
-            //} else {
-            color = [0.37, 0.22, 0.40, 1.0];
-            //}
-            let test = self.encoder.encode(boid.position.x, boid.position.y);
-
-            instances.push(Instance {
-                x: test.0,
-                y: test.1,
-                width: 0.05,
-                height: 0.05,
-                angle: ang - std::f32::consts::FRAC_PI_2,
-                color,
-            });
+            let position = self.current.positions[index];
+            let velocity = self.current.velocities[index];
+            let ang = velocity.y.atan2(velocity.x);
+            let species = self.species[boid.species];
+
+            // boids straddling the seam are drawn on both sides of it
+            let reach = species.size * self.dimensions.0.max(self.dimensions.1) as f32 / 2.0;
+            // the first image is the boid itself
+            let copies = if self.boundary.mode == BoundaryMode::Wrap {
+                4
+            } else {
+                1
+            };
+            let images =
+                Flock::wrapped_images(position, reach, self.dimensions.0, self.dimensions.1);
+
+            for image in images.take(copies) {
+                let test = self.encoder.encode(image.x, image.y);
+
+                instances.push(Instance {
+                    x: test.0,
+                    y: test.1,
+                    width: species.size,
+                    height: species.size,
+                    angle: ang - std::f32::consts::FRAC_PI_2,
+                    color: species.color,
+                });
+            }
 
             /*self.triangle.render(
                 &gl,
@@ -330,6 +1436,294 @@ impl Flock {
                 color,
             );*/
         }
-        self.triangle.render_instances(&gl, instances)
+        renderer.triangle.render_instances(gl, instances)
+    }
+}
+
+impl<'a> Step<'a> {
+    fn run(&self, next: &mut BoidBuffer, scratch: &mut Scratch) {
+        for index in 0..self.boids.len() {
+            next.set(index, self.boid(index, scratch));
+        }
+    }
+
+    #[cfg(feature = "parallel")]
+    fn run_parallel(&self, next: &mut BoidBuffer) {
+        use rayon::prelude::*;
+
+        let BoidBuffer {
+            positions,
+            velocities,
+            forces,
+            wander_angles,
+        } = next;
+        (
+            positions.par_iter_mut(),
+            velocities.par_iter_mut(),
+            forces.par_iter_mut(),
+            wander_angles.par_iter_mut(),
+        )
+            .into_par_iter()
+            .enumerate()
+            .for_each_init(Scratch::default, |scratch, (index, next)| {
+                let state = self.boid(index, scratch);
+                *next.0 = state.position;
+                *next.1 = state.velocity;
+                *next.2 = state.force;
+                *next.3 = state.wander_angle;
+            });
+    }
+
+    #[cfg(not(feature = "parallel"))]
+    fn run_parallel(&self, next: &mut BoidBuffer) {
+        self.run(next, &mut Scratch::default());
+    }
+
+    // the next state of one boid, reading only the previous step
+    fn boid(&self, index: usize, scratch: &mut Scratch) -> BoidState {
+        let mut boid = self.boids[index];
+        let mut state = self.current.get(index);
+        let mut rng = SplitMix64::for_entity(self.seed, self.step, index);
+        let (width, height) = self.dimensions;
+        let Scratch {
+            selected,
+            nearest,
+            neighbours,
+            nearby,
+        } = scratch;
+
+        // on a torus neighbours across the seam are seen through the edge
+        let wrap = self.boundary.mode == BoundaryMode::Wrap;
+        selected.clear();
+        match self.neighbour_mode {
+            NeighbourMode::Metric => Flock::query_wrapped(
+                self.quadtree,
+                wrap,
+                self.dimensions,
+                state.position,
+                boid.perception_size,
+                selected,
+            ),
+            NeighbourMode::Topological => {
+                let period = if wrap {
+                    Some((width as f32, height as f32))
+                } else {
+                    None
+                };
+                // one extra since the boid finds itself
+                self.quadtree.nearest_into(
+                    (state.position.x, state.position.y),
+                    self.neighbour_count + 1,
+                    period,
+                    nearest,
+                );
+                selected.extend(
+                    nearest
+                        .iter()
+                        .map(|&(_, other)| other)
+                        .filter(|&other| other != index)
+                        .take(self.neighbour_count),
+                );
+                selected.sort_unstable();
+                // the nearest count however far away they are, only separation
+                // keeps its reach
+                boid.alignment_radius = f32::INFINITY;
+                boid.cohesion_radius = f32::INFINITY;
+            }
+        }
+
+        neighbours.clear();
+        let interactions = &self.interactions[boid.species];
+        for &other in selected.iter() {
+            if other == index {
+                continue;
+            }
+            // neighbours are seen from this boid so every rule takes the
+            // short way around the torus
+            let mut position = self.current.positions[other];
+            if wrap {
+                position = Flock::nearest_image(state.position, position, width, height);
+            }
+            let offset = position - state.position;
+            if boid.sees(&state, offset) {
+                neighbours.push(Neighbour {
+                    offset,
+                    velocity: self.current.velocities[other],
+                    distance: offset.magnitude(),
+                    interaction: interactions[self.boids[other].species],
+                });
+            }
+        }
+
+        nearby.clear();
+        self.obstacle_tree.query_into(
+            &Bounds::Circle(state.position.x, state.position.y, boid.perception_size),
+            nearby,
+        );
+        nearby.sort_unstable();
+
+        let steering = Flock::steering(self.behaviours, &boid, &mut state, self.current, &mut rng);
+        let field = Flock::field(self.fields, state.position, self.step);
+        let surroundings = Surroundings {
+            neighbours,
+            obstacles: self.obstacles,
+            nearby,
+            steering,
+            field,
+        };
+        boid.step(
+            &mut rng,
+            state,
+            width as i32,
+            height as i32,
+            self.boundary,
+            &surroundings,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hawks_and_starlings_can_be_set_up_twice() {
+        let mut flock = Flock::headless(400, 300, 7);
+        flock.hawks_and_starlings(5);
+        flock.hawks_and_starlings(3);
+        assert_eq!(flock.species.len(), 2);
+        assert_eq!(flock.interactions.len(), 2);
+        let hawks = flock.boids.iter().filter(|boid| boid.species == 1).count();
+        assert_eq!(hawks, 3);
+    }
+
+    #[test]
+    fn boid_targets_follow_their_boid_when_others_are_removed() {
+        let mut flock = Flock::headless(400, 300, 3);
+        flock.resize(3);
+        flock.current.positions[0] = cgmath::Vector2::new(50.0, 50.0);
+        flock.current.positions[1] = cgmath::Vector2::new(200.0, 150.0);
+        flock.current.positions[2] = cgmath::Vector2::new(350.0, 250.0);
+        let pursue = flock.add_behaviour(Behaviour::Pursue(2), 1.0);
+        let evade = flock.add_behaviour(Behaviour::Evade(0), 1.0);
+        flock.add_behaviour(Behaviour::Seek(cgmath::Vector2::new(0.0, 0.0)), 1.0);
+
+        assert_eq!(
+            flock.remove_in_radius(cgmath::Vector2::new(50.0, 50.0), 10.0),
+            1
+        );
+        assert_eq!(flock.behaviours.len(), 2);
+        assert!(matches!(flock.behaviours[0], (id, Behaviour::Pursue(1), _) if id == pursue));
+        assert!(flock
+            .behaviours
+            .iter()
+            .all(|behaviour| behaviour.0 != evade));
+
+        flock.resize(1);
+        assert_eq!(flock.behaviours.len(), 1);
+        flock.update(400, 300);
+    }
+
+    #[test]
+    fn boids_near_a_seam_have_an_image_across_it() {
+        let images = |x, y| {
+            Flock::wrapped_images(cgmath::Vector2::new(x, y), 10.0, 400, 300).collect::<Vec<_>>()
+        };
+        assert_eq!(
+            images(200.0, 150.0),
+            vec![cgmath::Vector2::new(200.0, 150.0)]
+        );
+        assert_eq!(
+            images(395.0, 150.0),
+            vec![
+                cgmath::Vector2::new(395.0, 150.0),
+                cgmath::Vector2::new(-5.0, 150.0)
+            ]
+        );
+        assert_eq!(images(5.0, 295.0).len(), 4);
+        assert!(images(5.0, 295.0).contains(&cgmath::Vector2::new(405.0, -5.0)));
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn parallel_steps_match_serial_steps() {
+        let run = |parallel| {
+            let mut flock = Flock::headless(400, 300, 11);
+            flock.set_parallel(parallel);
+            assert_eq!(flock.parallel, parallel);
+            flock.add_behaviour(
+                Behaviour::Wander(crate::simulations::Wander {
+                    distance: 20.0,
+                    radius: 10.0,
+                    jitter: 0.3,
+                }),
+                0.5,
+            );
+            flock.set_collision_avoidance(4.0, 10.0);
+            for _ in 0..50 {
+                flock.update(400, 300);
+            }
+            (flock.current.positions, flock.current.velocities)
+        };
+        assert_eq!(run(false), run(true));
+    }
+
+    #[test]
+    fn boids_with_collision_avoidance_never_overlap() {
+        let radius = 8.0;
+        let mut flock = Flock::headless(300, 200, 40);
+        flock.resize(80);
+        flock.set_collision_avoidance(radius, 10.0);
+        // everyone heads for the same spot to pack the crowd in
+        flock.add_behaviour(Behaviour::Seek(cgmath::Vector2::new(150.0, 100.0)), 1.0);
+        for step in 0..100 {
+            flock.update(300, 200);
+            let positions = &flock.current.positions;
+            for a in 0..positions.len() {
+                for b in a + 1..positions.len() {
+                    let other = Flock::nearest_image(positions[a], positions[b], 300, 200);
+                    assert!(
+                        (other - positions[a]).magnitude() >= 2.0 * radius - 0.01,
+                        "boids {} and {} overlap after step {}",
+                        a,
+                        b,
+                        step
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn boids_pressed_against_an_edge_never_overlap() {
+        let radius = 8.0;
+        for &mode in &[
+            BoundaryMode::Bounce,
+            BoundaryMode::Steer,
+            BoundaryMode::Respawn,
+        ] {
+            let mut flock = Flock::headless(300, 200, 41);
+            flock.resize(60);
+            flock.set_boundary_mode(mode);
+            flock.set_collision_avoidance(radius, 10.0);
+            // everyone heads for a spot past the left edge
+            flock.add_behaviour(Behaviour::Seek(cgmath::Vector2::new(-100.0, 100.0)), 1.0);
+            for step in 0..150 {
+                flock.update(300, 200);
+                let positions = &flock.current.positions;
+                for a in 0..positions.len() {
+                    for b in a + 1..positions.len() {
+                        assert!(
+                            (positions[b] - positions[a]).magnitude() >= 2.0 * radius - 0.01,
+                            "boids {} and {} overlap after step {} in {:?}",
+                            a,
+                            b,
+                            step,
+                            mode
+                        );
+                    }
+                }
+            }
+        }
     }
 }
@@ -0,0 +1,223 @@
+use cgmath::prelude::*;
+use cgmath::{Matrix4, Point3, Quaternion, Vector3};
+use rand::prelude::*;
+use web_sys::WebGlRenderingContext as GL;
+
+use crate::{
+    rendering::Camera, rendering::Instance3D, rendering::Mesh, simulations::rules,
+    simulations::Boid, simulations::Interaction, simulations::Neighbour, uniform_grid::UniformGrid,
+    utils::limit, utils::set_magnitude,
+};
+
+// a thin pyramid pointing along +x, the way boids head
+const BOID_VERTICES: [f32; 12] = [
+    1.0, 0.0, 0.0, -0.5, 0.35, 0.0, -0.5, -0.2, 0.3, -0.5, -0.2, -0.3,
+];
+const BOID_INDICES: [u16; 12] = [0, 1, 2, 0, 2, 3, 0, 3, 1, 1, 3, 2];
+
+/// The flock in a box instead of on a plane. Boids follow the same alignment,
+/// cohesion and separation rules as the 2D flock with the same parameters,
+/// and turn back before leaving the box.
+pub struct Flock3D {
+    size: Vector3<f32>,
+    // parameters shared by every boid
+    boid: Boid,
+    positions: Vec<Vector3<f32>>,
+    velocities: Vec<Vector3<f32>>,
+    // written during a step while the last one is read, then swapped in
+    next_positions: Vec<Vector3<f32>>,
+    next_velocities: Vec<Vector3<f32>>,
+    grid: UniformGrid,
+    found: Vec<usize>,
+    neighbours: Vec<Neighbour<Vector3<f32>>>,
+    // distance from the walls at which boids start turning back
+    margin: f32,
+    rng: StdRng,
+    camera: Camera,
+    color: [f32; 4],
+    boid_size: f32,
+    renderer: Option<Mesh>,
+}
+
+impl Flock3D {
+    pub fn new(gl: &GL, width: u32, height: u32) -> Self {
+        let mut flock = Flock3D::headless(600.0, 300, rand::thread_rng().gen());
+        flock.camera.aspect = width as f32 / height as f32;
+        flock.renderer = Some(Mesh::new(gl, &BOID_VERTICES, &BOID_INDICES));
+        flock
+    }
+
+    /// A flock of `count` boids in a cube of side `size`, without any GL
+    /// resources. The same seed always gives the same flock.
+    pub fn headless(size: f32, count: usize, seed: u64) -> Self {
+        let size = Vector3::new(size, size, size);
+        let boid = Boid::new(0);
+        let center = Point3::new(size.x / 2.0, size.y / 2.0, size.z / 2.0);
+        let mut flock = Self {
+            size,
+            boid,
+            positions: Vec::new(),
+            velocities: Vec::new(),
+            next_positions: Vec::new(),
+            next_velocities: Vec::new(),
+            grid: UniformGrid::new(size, boid.perception_size),
+            found: Vec::new(),
+            neighbours: Vec::new(),
+            margin: 50.0,
+            rng: StdRng::seed_from_u64(seed),
+            camera: Camera::new(center, size.x * 1.4, 1.0),
+            color: [0.37, 0.22, 0.40, 1.0],
+            boid_size: 6.0,
+            renderer: None,
+        };
+        flock.resize(count);
+        flock
+    }
+
+    /// Spawns boids at random or drops the newest ones until there are `count`.
+    pub fn resize(&mut self, count: usize) {
+        self.positions.truncate(count);
+        self.velocities.truncate(count);
+        while self.positions.len() < count {
+            let position = Vector3::new(
+                self.rng.gen::<f32>() * self.size.x,
+                self.rng.gen::<f32>() * self.size.y,
+                self.rng.gen::<f32>() * self.size.z,
+            );
+            let velocity = Vector3::new(
+                self.rng.gen::<f32>() * 2.0 - 1.0,
+                self.rng.gen::<f32>() * 2.0 - 1.0,
+                self.rng.gen::<f32>() * 2.0 - 1.0,
+            );
+            self.positions.push(position);
+            self.velocities.push(velocity);
+        }
+        self.next_positions = self.positions.clone();
+        self.next_velocities = self.velocities.clone();
+    }
+
+    /// Turns the camera around the middle of the box, in radians.
+    pub fn orbit_camera(&mut self, yaw: f32, pitch: f32) {
+        self.camera.orbit(yaw, pitch);
+    }
+
+    pub fn set_camera_distance(&mut self, distance: f32) {
+        self.camera.distance = distance.max(1.0);
+        self.camera.far = self.camera.distance * 4.0;
+    }
+
+    // turn back towards the middle once inside the margin, harder the deeper in
+    fn contain(&self, position: Vector3<f32>, velocity: Vector3<f32>) -> Vector3<f32> {
+        let push = |value: f32, length: f32| {
+            if value < self.margin {
+                1.0 - value / self.margin
+            } else if value > length - self.margin {
+                -(1.0 - (length - value) / self.margin)
+            } else {
+                0.0
+            }
+        };
+        let push = Vector3::new(
+            push(position.x, self.size.x),
+            push(position.y, self.size.y),
+            push(position.z, self.size.z),
+        );
+        if push == Vector3::zero() {
+            return push;
+        }
+        let urgency = push.magnitude().min(1.0);
+        let desired = set_magnitude(
+            velocity + push * self.boid.max_speed * 2.0,
+            self.boid.max_speed,
+        );
+        limit(desired - velocity, self.boid.avoidance_force * urgency)
+    }
+
+    pub fn update(&mut self, width: i32, height: i32) {
+        self.camera.aspect = width as f32 / height as f32;
+        self.grid.rebuild(&self.positions);
+
+        let boid = self.boid;
+        for index in 0..self.positions.len() {
+            let position = self.positions[index];
+            let velocity = self.velocities[index];
+
+            self.found.clear();
+            self.grid
+                .query_into(position, boid.perception_size, &mut self.found);
+            self.neighbours.clear();
+            for &other in &self.found {
+                let offset = self.positions[other] - position;
+                let distance = offset.magnitude();
+                if other != index && distance <= boid.perception_size {
+                    self.neighbours.push(Neighbour {
+                        offset,
+                        velocity: self.velocities[other],
+                        distance,
+                        interaction: Interaction::Flock,
+                    });
+                }
+            }
+
+            let force = rules::align(
+                velocity,
+                &self.neighbours,
+                boid.alignment_radius,
+                boid.max_speed,
+                boid.alignment_force,
+            ) + rules::cohesion(
+                velocity,
+                &self.neighbours,
+                boid.cohesion_radius,
+                boid.max_speed,
+                boid.cohesion_force,
+            ) + rules::seperation(
+                velocity,
+                &self.neighbours,
+                boid.seperation_radius,
+                boid.max_speed,
+                boid.seperation_force,
+            ) + self.contain(position, velocity);
+
+            let mut velocity = limit(velocity + force, boid.max_speed);
+            if velocity.magnitude() < boid.max_speed * 0.25 {
+                velocity = set_magnitude(velocity, boid.max_speed * 0.25);
+            }
+            let position = position + velocity;
+            // the margin turns boids well before this, it only catches stragglers
+            self.next_positions[index] = Vector3::new(
+                position.x.max(0.0).min(self.size.x),
+                position.y.max(0.0).min(self.size.y),
+                position.z.max(0.0).min(self.size.z),
+            );
+            self.next_velocities[index] = velocity;
+        }
+
+        std::mem::swap(&mut self.positions, &mut self.next_positions);
+        std::mem::swap(&mut self.velocities, &mut self.next_velocities);
+    }
+
+    pub fn render(&self, gl: &GL) {
+        let renderer = match &self.renderer {
+            Some(renderer) => renderer,
+            None => return,
+        };
+
+        let mut instances = Vec::with_capacity(self.positions.len());
+        for (position, velocity) in self.positions.iter().zip(&self.velocities) {
+            let heading = if velocity.magnitude2() > 0.0 {
+                velocity.normalize()
+            } else {
+                Vector3::unit_x()
+            };
+            let rotation = Quaternion::from_arc(Vector3::unit_x(), heading, None);
+            instances.push(Instance3D {
+                model: Matrix4::from_translation(*position)
+                    * Matrix4::from(rotation)
+                    * Matrix4::from_scale(self.boid_size),
+                color: self.color,
+            });
+        }
+        renderer.render_instances(gl, &self.camera, instances);
+    }
+}
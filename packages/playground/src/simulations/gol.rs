@@ -2,17 +2,29 @@ use rand::prelude::*;
 use web_sys::WebGlRenderingContext as GL;
 
 use crate::rendering::{Rectangle, Instance};
+use crate::utils::thread_pool;
 
 pub struct GoL {
     dimensions: (u32, u32),
     tiles: Vec<bool>,
-    renderer: Rectangle,
+    tiles_buffer: Vec<bool>,
+    // step rows on the rayon thread pool
+    parallel: bool,
+    renderer: Option<Rectangle>,
 }
 
 impl GoL {
     pub fn new(gl: &GL, width: u32, height: u32) -> Self {
+        let mut gol = GoL::headless(width, height, rand::thread_rng().gen());
+        gol.renderer = Some(Rectangle::new(gl));
+        gol
+    }
+
+    /// A board without any GL resources, seeded so the same seed always gives
+    /// the same board.
+    pub fn headless(width: u32, height: u32, seed: u64) -> Self {
         let mut tiles = Vec::<bool>::new();
-        let mut rng = rand::thread_rng();
+        let mut rng = StdRng::seed_from_u64(seed);
 
         for _ in 0..width * height {
             tiles.push(rng.gen::<f32>() > 0.9);
@@ -20,11 +32,20 @@ impl GoL {
 
         Self {
             dimensions: (width, height),
+            tiles_buffer: tiles.clone(),
             tiles,
-            renderer: Rectangle::new(&gl),
+            parallel: thread_pool::is_ready(),
+            renderer: None,
         }
     }
 
+    /// Steps rows on the rayon thread pool, ignored without the `parallel`
+    /// feature or until the thread pool is ready. Every tile only reads the
+    /// previous generation so the result is the same either way.
+    pub fn set_parallel(&mut self, parallel: bool) {
+        self.parallel = parallel && thread_pool::is_ready();
+    }
+
     fn decode(&self, index: usize) -> (u32, u32) {
         (
             index as u32 % self.dimensions.0,
@@ -32,23 +53,25 @@ impl GoL {
         )
     }
 
-    fn encode(&self, x: i32, y: i32) -> usize {
+    // Associated rather than methods so rows can be stepped on other threads
+    // without sharing the renderer.
+    fn encode(dimensions: (u32, u32), x: i32, y: i32) -> usize {
         // If location is negative loop back to end of corresponding coordinate space.
         let x = if x < 0 {
-            (self.dimensions.0 as i32 + x) as u32
+            (dimensions.0 as i32 + x) as u32
         } else {
             x as u32
         };
         let y = if y < 0 {
-            (self.dimensions.1 as i32 + y) as u32
+            (dimensions.1 as i32 + y) as u32
         } else {
             y as u32
         };
         // Perform a modulo on the length of the tiles vector to loop coordinate space.
-        (y * self.dimensions.0 + x) as usize % self.tiles.len() as usize
+        (y * dimensions.0 + x) as usize % (dimensions.0 * dimensions.1) as usize
     }
 
-    fn get_active_neighbor_count(&self, x: u32, y: u32) -> u32 {
+    fn get_active_neighbor_count(tiles: &[bool], dimensions: (u32, u32), x: u32, y: u32) -> u32 {
         //wrap screen maybe?
         let mut count = 0;
         for horizontal_offset in -1..2 {
@@ -56,23 +79,49 @@ impl GoL {
                 if horizontal_offset == 0 && vertical_offset == 0 {
                     continue;
                 }
-                count += self.tiles
-                    [self.encode(x as i32 + horizontal_offset, y as i32 + vertical_offset)]
-                    as u32;
+                count += tiles[GoL::encode(
+                    dimensions,
+                    x as i32 + horizontal_offset,
+                    y as i32 + vertical_offset,
+                )] as u32;
             }
         }
         count
     }
 
+    fn step_row(tiles: &[bool], dimensions: (u32, u32), y: u32, row: &mut [bool]) {
+        for (x, next) in row.iter_mut().enumerate() {
+            let tile = tiles[(y * dimensions.0) as usize + x];
+            let active_neighbor_count =
+                GoL::get_active_neighbor_count(tiles, dimensions, x as u32, y);
+            *next = active_neighbor_count == 3 || (tile && active_neighbor_count == 2);
+        }
+    }
+
     pub fn update(&mut self, width: i32, height: i32) {
-        let mut tiles_buffer = self.tiles.clone();
-        for (index, tile) in self.tiles.iter().enumerate() {
-            let (x, y) = self.decode(index);
-            let active_neighbor_count = self.get_active_neighbor_count(x, y);
-            tiles_buffer[index] =
-                active_neighbor_count == 3 || (*tile && active_neighbor_count == 2);
+        // nothing to step, and rows of no tiles can't be split into chunks
+        if self.tiles.is_empty() {
+            return;
+        }
+        let tiles = &self.tiles;
+        let dimensions = self.dimensions;
+        let row_length = dimensions.0 as usize;
+        #[cfg(feature = "parallel")]
+        {
+            if self.parallel {
+                use rayon::prelude::*;
+                self.tiles_buffer
+                    .par_chunks_mut(row_length)
+                    .enumerate()
+                    .for_each(|(y, row)| GoL::step_row(tiles, dimensions, y as u32, row));
+                std::mem::swap(&mut self.tiles, &mut self.tiles_buffer);
+                return;
+            }
+        }
+        for (y, row) in self.tiles_buffer.chunks_mut(row_length).enumerate() {
+            GoL::step_row(tiles, dimensions, y as u32, row);
         }
-        self.tiles = tiles_buffer;
+        std::mem::swap(&mut self.tiles, &mut self.tiles_buffer);
     }
 
     pub fn render(&self, gl: &GL) {
@@ -98,6 +147,39 @@ impl GoL {
                 color,
             });
         }
-        self.renderer.render_instances(gl, instances);
+        if let Some(renderer) = &self.renderer {
+            renderer.render_instances(gl, instances);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn parallel_steps_match_serial_steps() {
+        let run = |parallel| {
+            let mut gol = GoL::headless(97, 61, 7);
+            gol.set_parallel(parallel);
+            assert_eq!(gol.parallel, parallel);
+            for _ in 0..50 {
+                gol.update(97, 61);
+            }
+            gol.tiles
+        };
+        let serial = run(false);
+        assert!(serial.iter().any(|&tile| tile));
+        assert_eq!(serial, run(true));
+    }
+
+    #[test]
+    fn empty_boards_step() {
+        for &(width, height) in &[(0, 0), (0, 10), (10, 0)] {
+            let mut gol = GoL::headless(width, height, 1);
+            gol.update(width as i32, height as i32);
+            assert!(gol.tiles.is_empty());
+        }
     }
 }
@@ -0,0 +1,121 @@
+use std::cell::Cell;
+
+use crate::{
+    simulations::Region,
+    utils::{CoordinateEncoder, FlatEncoder},
+};
+
+/// Temperature of every cell of a grid world, tiles and empty cells alike.
+/// Heat flows between neighbouring cells in proportion to how well both
+/// conduct it, and empty cells slowly settle back to the ambient temperature.
+/// Cells are `Cell`s so temperatures can follow tiles around while the tiles
+/// are being stepped.
+pub struct HeatField {
+    encoder: FlatEncoder,
+    ambient: f32,
+    temperatures: Vec<Cell<f32>>,
+    // written by a diffusion step then copied back
+    next: Vec<f32>,
+}
+
+impl HeatField {
+    // share of the difference with ambient an empty cell loses each step
+    const AMBIENT_PULL: f32 = 0.01;
+    // a region whose cells all change by less than this in a step has settled
+    const SETTLED: f32 = 0.05;
+
+    pub fn new(width: u32, height: u32, ambient: f32) -> Self {
+        Self {
+            encoder: FlatEncoder {
+                dimensions: (width, height),
+            },
+            ambient,
+            temperatures: vec![Cell::new(ambient); (width * height) as usize],
+            next: vec![ambient; (width * height) as usize],
+        }
+    }
+
+    pub fn ambient(&self) -> f32 {
+        self.ambient
+    }
+
+    /// Temperature of a cell, ambient outside the world.
+    pub fn get(&self, x: i32, y: i32) -> f32 {
+        match self.encoder.encode(x, y) {
+            Some(index) => self.temperatures[index].get(),
+            None => self.ambient,
+        }
+    }
+
+    pub fn set(&self, x: i32, y: i32, temperature: f32) {
+        if let Some(index) = self.encoder.encode(x, y) {
+            self.temperatures[index].set(temperature);
+        }
+    }
+
+    /// Trades the temperatures of two cells, for when their tiles swap.
+    pub fn swap(&self, a: (i32, i32), b: (i32, i32)) {
+        if let (Some(a), Some(b)) = (self.encoder.encode(a.0, a.1), self.encoder.encode(b.0, b.1)) {
+            self.temperatures[a].swap(&self.temperatures[b]);
+        }
+    }
+
+    /// Puts every cell back at the ambient temperature.
+    pub fn reset(&mut self) {
+        for temperature in &self.temperatures {
+            temperature.set(self.ambient);
+        }
+    }
+
+    /// Moves heat one step between neighbouring cells within `regions`.
+    /// `conductivity` gives each cell's conductivity from 0 to 1 by cell index
+    /// and whether it's empty. Edges don't let heat through. Returns whether
+    /// each region's temperatures are still changing.
+    pub fn diffuse<F: Fn(usize) -> (f32, bool)>(
+        &mut self,
+        regions: &[Region],
+        conductivity: F,
+    ) -> Vec<bool> {
+        let width = self.encoder.dimensions.0 as i32;
+        let mut changing = vec![false; regions.len()];
+        for (&(x0, y0, x1, y1), changing) in regions.iter().zip(changing.iter_mut()) {
+            for y in y0 as i32..y1 as i32 {
+                for x in x0 as i32..x1 as i32 {
+                    let index = (y * width + x) as usize;
+                    let temperature = self.temperatures[index].get();
+                    let (own, empty) = conductivity(index);
+                    let mut flow = 0.0;
+                    for &(dx, dy) in &[(0, -1), (-1, 0), (1, 0), (0, 1)] {
+                        if let Some(other) = self.encoder.encode(x + dx, y + dy) {
+                            let (theirs, _) = conductivity(other);
+                            // in series, the worse conductor decides
+                            let shared = if own + theirs > 0.0 {
+                                2.0 * own * theirs / (own + theirs)
+                            } else {
+                                0.0
+                            };
+                            flow += shared * (self.temperatures[other].get() - temperature);
+                        }
+                    }
+                    // a quarter per neighbour keeps the step from overshooting
+                    let mut next = temperature + flow * 0.25;
+                    if empty {
+                        next += (self.ambient - next) * HeatField::AMBIENT_PULL;
+                    }
+                    *changing |= (next - temperature).abs() > HeatField::SETTLED;
+                    self.next[index] = next;
+                }
+            }
+        }
+        // only once every region is done, so the order they're in doesn't matter
+        for &(x0, y0, x1, y1) in regions {
+            for y in y0..y1 {
+                let row = (y * width as u32) as usize;
+                for index in row + x0 as usize..row + x1 as usize {
+                    self.temperatures[index].set(self.next[index]);
+                }
+            }
+        }
+        changing
+    }
+}
@@ -0,0 +1,547 @@
+use serde::de::{Deserializer, Error};
+use serde::Deserialize;
+use std::collections::HashMap;
+
+/// How a material moves through the sand world.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Phase {
+    /// Falls straight down or slides off diagonally, piling up.
+    Powder,
+    /// Falls like a powder, then spreads sideways to level out.
+    Liquid,
+    /// Rises and spreads sideways.
+    Gas,
+    /// Never moves.
+    Solid,
+}
+
+/// Yes or no properties of a material, packed into bits. In JSON they are a
+/// list of names, e.g. `["flammable"]`.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Flags(u8);
+
+impl Flags {
+    pub const NONE: Flags = Flags(0);
+    /// Catches fire from materials that ignite things.
+    pub const FLAMMABLE: Flags = Flags(1);
+    /// Acid can dissolve it.
+    pub const SOLUBLE: Flags = Flags(1 << 1);
+    /// Dissolves soluble materials it touches, and is used up doing it.
+    pub const CORROSIVE: Flags = Flags(1 << 2);
+    /// Sets flammable materials it touches on fire.
+    pub const IGNITES: Flags = Flags(1 << 3);
+    /// Stays at its own temperature however much heat it gives off.
+    pub const HEAT_SOURCE: Flags = Flags(1 << 4);
+
+    const NAMES: [(&'static str, Flags); 5] = [
+        ("flammable", Flags::FLAMMABLE),
+        ("soluble", Flags::SOLUBLE),
+        ("corrosive", Flags::CORROSIVE),
+        ("ignites", Flags::IGNITES),
+        ("heat_source", Flags::HEAT_SOURCE),
+    ];
+
+    pub fn contains(self, other: Flags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn with(self, other: Flags) -> Flags {
+        Flags(self.0 | other.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for Flags {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let names = Vec::<String>::deserialize(deserializer)?;
+        let mut flags = Flags::NONE;
+        for name in names {
+            match Flags::NAMES
+                .iter()
+                .find(|(flag_name, _)| *flag_name == name)
+            {
+                Some(&(_, flag)) => flags = flags.with(flag),
+                None => return Err(D::Error::custom(format!("unknown flag `{}`", name))),
+            }
+        }
+        Ok(flags)
+    }
+}
+
+/// Everything the sand world needs to know about one kind of tile.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Material {
+    pub name: String,
+    /// Heavier materials sink through lighter liquids and gases.
+    pub density: f32,
+    pub phase: Phase,
+    /// How many cells a liquid or gas can spread sideways in one step.
+    #[serde(default = "Material::default_dispersion")]
+    pub dispersion: u32,
+    /// Tiles pick one of these so a pile doesn't look flat.
+    pub colors: Vec<[f32; 4]>,
+    #[serde(default)]
+    pub flags: Flags,
+    /// Steps a tile lasts before turning into `decays_into`, 0 for forever.
+    /// Each tile lives somewhere between this and half again as long.
+    #[serde(default)]
+    pub lifetime: u16,
+    /// Name of the material a tile becomes when its lifetime runs out, empty
+    /// to vanish.
+    #[serde(default)]
+    pub decays_into: String,
+    /// Temperature of a new tile, in degrees.
+    #[serde(default = "Material::default_temperature")]
+    pub temperature: f32,
+    /// How easily heat passes through, from 0 to 1.
+    #[serde(default = "Material::default_conductivity")]
+    pub conductivity: f32,
+    /// What a tile turns into once hotter than a temperature, like water
+    /// boiling.
+    #[serde(default)]
+    pub above: Option<Transition>,
+    /// What a tile turns into once colder than a temperature, like water
+    /// freezing.
+    #[serde(default)]
+    pub below: Option<Transition>,
+}
+
+/// A change of material at a temperature, by the new material's name.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Transition {
+    pub temperature: f32,
+    pub into: String,
+}
+
+/// What happens when two materials touch. Every step a tile touching the
+/// other material turns into `into` and the other tile into `other_into`
+/// with probability `chance`.
+#[derive(Debug, Copy, Clone)]
+pub struct Reaction {
+    pub into: u8,
+    pub other_into: u8,
+    pub chance: f32,
+}
+
+// a reaction as written in JSON, by material names
+#[derive(Deserialize)]
+struct ReactionDescription {
+    between: (String, String),
+    into: (String, String),
+    chance: f32,
+}
+
+impl Material {
+    pub fn new(name: &str, density: f32, phase: Phase, colors: Vec<[f32; 4]>) -> Self {
+        Self {
+            name: name.to_string(),
+            density,
+            phase,
+            dispersion: Material::default_dispersion(),
+            colors,
+            flags: Flags::NONE,
+            lifetime: 0,
+            decays_into: String::new(),
+            temperature: Material::default_temperature(),
+            conductivity: Material::default_conductivity(),
+            above: None,
+            below: None,
+        }
+    }
+
+    pub fn with_dispersion(mut self, dispersion: u32) -> Self {
+        self.dispersion = dispersion;
+        self
+    }
+
+    pub fn with_flags(mut self, flags: Flags) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    pub fn with_lifetime(mut self, lifetime: u16, decays_into: &str) -> Self {
+        self.lifetime = lifetime;
+        self.decays_into = decays_into.to_string();
+        self
+    }
+
+    pub fn with_heat(mut self, temperature: f32, conductivity: f32) -> Self {
+        self.temperature = temperature;
+        self.conductivity = conductivity.clamp(0.0, 1.0);
+        self
+    }
+
+    pub fn with_above(mut self, temperature: f32, into: &str) -> Self {
+        self.above = Some(Transition {
+            temperature,
+            into: into.to_string(),
+        });
+        self
+    }
+
+    pub fn with_below(mut self, temperature: f32, into: &str) -> Self {
+        self.below = Some(Transition {
+            temperature,
+            into: into.to_string(),
+        });
+        self
+    }
+
+    fn default_temperature() -> f32 {
+        20.0
+    }
+
+    fn default_conductivity() -> f32 {
+        0.2
+    }
+
+    fn default_dispersion() -> u32 {
+        1
+    }
+
+    /// The colour of a tile, varying with `seed` between the material's
+    /// colours.
+    pub fn color(&self, seed: usize) -> [f32; 4] {
+        if self.colors.is_empty() {
+            [1.0, 0.0, 1.0, 1.0]
+        } else {
+            self.colors[seed % self.colors.len()]
+        }
+    }
+
+    /// Whether a tile of this material moving along gravity can trade places
+    /// with a tile of `other`. Only liquids and gases give way, to anything
+    /// denser.
+    pub fn sinks_through(&self, other: &Material) -> bool {
+        self.phase != Phase::Solid
+            && (other.phase == Phase::Liquid || other.phase == Phase::Gas)
+            && self.density > other.density
+    }
+
+    /// Whether a tile of this material moving against gravity can trade
+    /// places with a tile of `other`, like a bubble rising through water.
+    pub fn rises_through(&self, other: &Material) -> bool {
+        self.phase == Phase::Gas
+            && (other.phase == Phase::Liquid || other.phase == Phase::Gas)
+            && self.density < other.density
+    }
+}
+
+// a temperature and the id of the material a tile turns into past it
+type Threshold = Option<(f32, u8)>;
+
+/// The materials of a sand world, indexed by tile id. Id 0 is left empty so
+/// an id of 0 can stand for no tile.
+#[derive(Debug, Clone)]
+pub struct MaterialTable {
+    materials: Vec<Material>,
+    // decays[id] is the id `decays_into` names, kept up to date as materials
+    // are added
+    decays: Vec<u8>,
+    // transitions[id] is `above` and `below` with the names looked up, a
+    // transition into a missing material is dropped
+    transitions: Vec<(Threshold, Threshold)>,
+    // keyed by (material, material touching it), each reaction is stored
+    // from both sides
+    reactions: HashMap<(u8, u8), Reaction>,
+}
+
+impl MaterialTable {
+    pub const EMPTY: u8 = 0;
+    pub const SAND: u8 = 1;
+    pub const WATER: u8 = 2;
+    pub const STONE: u8 = 3;
+    pub const FIRE: u8 = 4;
+    pub const STEAM: u8 = 6;
+    pub const WOOD: u8 = 7;
+    pub const OIL: u8 = 8;
+    pub const LAVA: u8 = 9;
+    #[cfg(test)]
+    pub const ACID: u8 = 10;
+    #[cfg(test)]
+    pub const ICE: u8 = 11;
+
+    pub fn get(&self, id: u8) -> &Material {
+        &self.materials[id as usize]
+    }
+
+    /// The id a tile of material `id` turns into when its lifetime runs out.
+    pub fn decays_into(&self, id: u8) -> u8 {
+        self.decays[id as usize]
+    }
+
+    /// The material a tile of `id` turns into at `temperature`, if it changes.
+    pub fn transition(&self, id: u8, temperature: f32) -> Option<u8> {
+        match self.transitions[id as usize] {
+            (Some((above, into)), _) if temperature > above => Some(into),
+            (_, Some((below, into))) if temperature < below => Some(into),
+            _ => None,
+        }
+    }
+
+    /// How a tile of material `id` reacts to a touching tile of `other`.
+    /// Pairs missing from the table fall back on flags: corrosive materials
+    /// dissolve soluble ones and igniting materials set flammable ones alight.
+    pub fn reaction(&self, id: u8, other: u8) -> Option<Reaction> {
+        if let Some(reaction) = self.reactions.get(&(id, other)) {
+            return Some(*reaction);
+        }
+        let (flags, other_flags) = (self.get(id).flags, self.get(other).flags);
+        if flags.contains(Flags::CORROSIVE) && other_flags.contains(Flags::SOLUBLE) {
+            Some(Reaction {
+                into: MaterialTable::EMPTY,
+                other_into: MaterialTable::EMPTY,
+                chance: 0.1,
+            })
+        } else if flags.contains(Flags::IGNITES) && other_flags.contains(Flags::FLAMMABLE) {
+            Some(Reaction {
+                into: id,
+                other_into: MaterialTable::FIRE,
+                chance: 0.05,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Makes tiles of `first` and `second` react when they touch, see
+    /// `Reaction`. Replaces any earlier reaction between the two.
+    pub fn add_reaction(&mut self, first: u8, second: u8, reaction: Reaction) {
+        self.reactions.insert((first, second), reaction);
+        self.reactions.insert(
+            (second, first),
+            Reaction {
+                into: reaction.other_into,
+                other_into: reaction.into,
+                chance: reaction.chance,
+            },
+        );
+    }
+
+    /// Adds a reaction described in JSON, like
+    /// `{"between": ["lava", "water"], "into": ["stone", "steam"],
+    /// "chance": 0.5}`. Every name must already be in the table.
+    pub fn add_reaction_json(&mut self, json: &str) -> Result<(), String> {
+        let description: ReactionDescription =
+            serde_json::from_str(json).map_err(|error| error.to_string())?;
+        let find = |name: &String| {
+            self.find(name)
+                .ok_or_else(|| format!("unknown material `{}`", name))
+        };
+        let (first, second) = (find(&description.between.0)?, find(&description.between.1)?);
+        let reaction = Reaction {
+            into: find(&description.into.0)?,
+            other_into: find(&description.into.1)?,
+            chance: description.chance,
+        };
+        self.add_reaction(first, second, reaction);
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.materials.len()
+    }
+
+    /// The id of the material called `name`.
+    pub fn find(&self, name: &str) -> Option<u8> {
+        self.materials
+            .iter()
+            .position(|material| material.name == name)
+            .map(|id| id as u8)
+    }
+
+    /// Adds a material, or replaces the one with the same name, and returns
+    /// its id. Ids are a byte so there's room for 256 materials. Conductivity
+    /// is clamped to 0 to 1, past which heat diffusion overshoots and blows up.
+    pub fn add(&mut self, mut material: Material) -> Result<u8, String> {
+        material.conductivity = material.conductivity.clamp(0.0, 1.0);
+        if let Some(id) = self.find(&material.name) {
+            if id != MaterialTable::EMPTY {
+                self.materials[id as usize] = material;
+                self.resolve_names();
+                return Ok(id);
+            }
+            return Err("the empty material can't be replaced".to_string());
+        }
+        if self.materials.len() > u8::MAX as usize {
+            return Err("no more room for materials".to_string());
+        }
+        self.materials.push(material);
+        self.resolve_names();
+        Ok((self.materials.len() - 1) as u8)
+    }
+
+    // looks up every material named by another again, a material added later
+    // can be what an earlier one decays or melts into
+    fn resolve_names(&mut self) {
+        let decays = self
+            .materials
+            .iter()
+            .map(|material| {
+                self.find(&material.decays_into)
+                    .unwrap_or(MaterialTable::EMPTY)
+            })
+            .collect();
+        let resolve = |transition: &Option<Transition>| {
+            transition.as_ref().and_then(|transition| {
+                self.find(&transition.into)
+                    .map(|into| (transition.temperature, into))
+            })
+        };
+        let transitions = self
+            .materials
+            .iter()
+            .map(|material| (resolve(&material.above), resolve(&material.below)))
+            .collect();
+        self.decays = decays;
+        self.transitions = transitions;
+    }
+
+    /// Adds a material described in JSON, like
+    /// `{"name": "salt", "density": 2.1, "phase": "powder",
+    /// "colors": [[1, 1, 1, 1]], "flags": ["soluble"]}`.
+    pub fn add_json(&mut self, json: &str) -> Result<u8, String> {
+        let material: Material = serde_json::from_str(json).map_err(|error| error.to_string())?;
+        self.add(material)
+    }
+}
+
+impl Default for MaterialTable {
+    fn default() -> Self {
+        let materials = vec![
+            Material::new("empty", 0.0, Phase::Gas, vec![[0.0, 0.0, 0.0, 0.0]])
+                .with_heat(20.0, 0.02),
+            Material::new(
+                "sand",
+                1.6,
+                Phase::Powder,
+                vec![
+                    [237.0 / 256.0, 201.0 / 256.0, 175.0 / 256.0, 1.0],
+                    [226.0 / 256.0, 188.0 / 256.0, 160.0 / 256.0, 1.0],
+                ],
+            )
+            .with_flags(Flags::SOLUBLE),
+            Material::new("water", 1.0, Phase::Liquid, vec![[0.0, 0.41, 0.58, 1.0]])
+                .with_dispersion(3)
+                .with_heat(20.0, 0.5)
+                .with_above(100.0, "steam")
+                .with_below(0.0, "ice"),
+            Material::new(
+                "stone",
+                2.5,
+                Phase::Solid,
+                vec![[0.45, 0.45, 0.48, 1.0], [0.40, 0.40, 0.43, 1.0]],
+            )
+            .with_flags(Flags::SOLUBLE)
+            .with_heat(20.0, 0.4)
+            .with_above(1000.0, "lava"),
+            Material::new(
+                "fire",
+                0.1,
+                Phase::Gas,
+                vec![
+                    [1.0, 0.35, 0.0, 1.0],
+                    [1.0, 0.6, 0.1, 1.0],
+                    [0.9, 0.2, 0.0, 1.0],
+                ],
+            )
+            .with_flags(Flags::IGNITES.with(Flags::HEAT_SOURCE))
+            .with_lifetime(20, "smoke")
+            .with_heat(600.0, 0.5),
+            Material::new(
+                "smoke",
+                0.3,
+                Phase::Gas,
+                vec![[0.25, 0.25, 0.25, 0.8], [0.3, 0.3, 0.3, 0.7]],
+            )
+            .with_dispersion(2)
+            .with_lifetime(80, "")
+            .with_heat(100.0, 0.1),
+            Material::new("steam", 0.5, Phase::Gas, vec![[0.8, 0.85, 0.9, 0.6]])
+                .with_dispersion(2)
+                .with_lifetime(300, "water")
+                .with_heat(150.0, 0.1)
+                .with_below(90.0, "water"),
+            Material::new(
+                "wood",
+                0.7,
+                Phase::Solid,
+                vec![[0.45, 0.28, 0.12, 1.0], [0.40, 0.24, 0.10, 1.0]],
+            )
+            .with_flags(Flags::FLAMMABLE.with(Flags::SOLUBLE))
+            .with_heat(20.0, 0.05),
+            Material::new("oil", 0.8, Phase::Liquid, vec![[0.25, 0.2, 0.1, 1.0]])
+                .with_dispersion(2)
+                .with_flags(Flags::FLAMMABLE)
+                .with_heat(20.0, 0.15),
+            Material::new(
+                "lava",
+                3.0,
+                Phase::Liquid,
+                vec![[1.0, 0.3, 0.0, 1.0], [0.9, 0.15, 0.0, 1.0]],
+            )
+            .with_flags(Flags::IGNITES)
+            .with_heat(1200.0, 0.3)
+            .with_below(700.0, "stone"),
+            Material::new("acid", 1.1, Phase::Liquid, vec![[0.5, 1.0, 0.2, 1.0]])
+                .with_dispersion(2)
+                .with_flags(Flags::CORROSIVE)
+                .with_heat(20.0, 0.4),
+            Material::new(
+                "ice",
+                0.9,
+                Phase::Solid,
+                vec![[0.75, 0.9, 1.0, 1.0], [0.7, 0.85, 0.95, 1.0]],
+            )
+            .with_heat(-10.0, 0.6)
+            .with_above(0.0, "water"),
+        ];
+        let mut table = Self {
+            materials,
+            decays: Vec::new(),
+            transitions: Vec::new(),
+            reactions: HashMap::new(),
+        };
+        table.resolve_names();
+
+        let reaction = |into, other_into, chance| Reaction {
+            into,
+            other_into,
+            chance,
+        };
+        use MaterialTable as M;
+        // wood smoulders, oil goes up at once
+        table.add_reaction(M::FIRE, M::WOOD, reaction(M::FIRE, M::FIRE, 0.02));
+        table.add_reaction(M::FIRE, M::OIL, reaction(M::FIRE, M::FIRE, 0.3));
+        table.add_reaction(M::FIRE, M::WATER, reaction(M::STEAM, M::WATER, 0.5));
+        table.add_reaction(M::LAVA, M::WATER, reaction(M::STONE, M::STEAM, 0.5));
+        table
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conductivity_is_clamped_to_one() {
+        let mut table = MaterialTable::default();
+        let copper = table
+            .add_json(
+                r#"{"name": "copper", "density": 9, "phase": "solid", "colors": [],
+                "conductivity": 5}"#,
+            )
+            .unwrap();
+        assert_eq!(table.get(copper).conductivity, 1.0);
+
+        let felt = Material::new("felt", 0.2, Phase::Solid, vec![]).with_heat(20.0, -1.0);
+        assert_eq!(felt.conductivity, 0.0);
+        let felt = table
+            .add(Material {
+                conductivity: 3.0,
+                ..felt
+            })
+            .unwrap();
+        assert_eq!(table.get(felt).conductivity, 1.0);
+    }
+}
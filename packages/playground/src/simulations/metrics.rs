@@ -0,0 +1,108 @@
+use cgmath::prelude::*;
+use cgmath::Vector2;
+use wasm_bindgen::prelude::*;
+
+/// Measures of how a flock moves as a whole, taken from a single step.
+#[wasm_bindgen]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct FlockMetrics {
+    /// Order parameter, 1 when every boid heads the same way and close to 0
+    /// when headings are random.
+    pub polarization: f32,
+    /// Normalized angular momentum around the flock's center, 1 when boids
+    /// mill around it and close to 0 when they don't.
+    pub angular_momentum: f32,
+    pub average_speed: f32,
+    /// Mean distance from a boid to its nearest neighbour.
+    pub nearest_neighbour: f32,
+    /// Groups of boids linked through boids within perception range.
+    pub clusters: u32,
+}
+
+fn heading(velocity: Vector2<f32>) -> Vector2<f32> {
+    if velocity.magnitude2() > 0.0 {
+        velocity.normalize()
+    } else {
+        velocity
+    }
+}
+
+pub fn polarization(velocities: &[Vector2<f32>]) -> f32 {
+    if velocities.is_empty() {
+        return 0.0;
+    }
+    let sum = velocities
+        .iter()
+        .fold(Vector2::new(0.0, 0.0), |sum, &velocity| {
+            sum + heading(velocity)
+        });
+    sum.magnitude() / velocities.len() as f32
+}
+
+pub fn angular_momentum(positions: &[Vector2<f32>], velocities: &[Vector2<f32>]) -> f32 {
+    if positions.is_empty() {
+        return 0.0;
+    }
+    let count = positions.len() as f32;
+    let center = positions
+        .iter()
+        .fold(Vector2::new(0.0, 0.0), |sum, &position| sum + position)
+        / count;
+    let sum: f32 = positions
+        .iter()
+        .zip(velocities)
+        .map(|(&position, &velocity)| {
+            let arm = heading(position - center);
+            let heading = heading(velocity);
+            arm.x * heading.y - arm.y * heading.x
+        })
+        .sum();
+    sum.abs() / count
+}
+
+pub fn average_speed(velocities: &[Vector2<f32>]) -> f32 {
+    if velocities.is_empty() {
+        return 0.0;
+    }
+    velocities
+        .iter()
+        .map(|velocity| velocity.magnitude())
+        .sum::<f32>()
+        / velocities.len() as f32
+}
+
+/// Union find over `0..len`, used to count connected groups.
+pub struct Components {
+    parents: Vec<usize>,
+    count: usize,
+}
+
+impl Components {
+    pub fn new(len: usize) -> Self {
+        Self {
+            parents: (0..len).collect(),
+            count: len,
+        }
+    }
+
+    fn root(&mut self, mut index: usize) -> usize {
+        while self.parents[index] != index {
+            // halve the path on the way up
+            self.parents[index] = self.parents[self.parents[index]];
+            index = self.parents[index];
+        }
+        index
+    }
+
+    pub fn join(&mut self, a: usize, b: usize) {
+        let (a, b) = (self.root(a), self.root(b));
+        if a != b {
+            self.parents[a] = b;
+            self.count -= 1;
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+}
@@ -1,10 +1,36 @@
+pub use boid_buffer::{BoidBuffer, BoidState, Neighbour};
+pub use chunks::{Chunks, Region, SandStats};
+pub use emitters::{Emitter, Scene, Shape, Sink};
 pub use falling_sand::FallingSand;
 pub use flock::Boid;
 pub use flock::Flock;
+pub use flock::{BoundaryMode, Interaction, NeighbourMode, Perception, Species};
+pub use flock3d::Flock3D;
 pub use gol::GoL;
+pub use heat::HeatField;
+pub use materials::{Flags, MaterialTable, Phase};
+pub use metrics::FlockMetrics;
+pub use obstacle::Obstacle;
+pub use orca::OrcaAgent;
 pub use simulation::Simulation;
+pub use steering::{Behaviour, Path, Wander};
+pub use trails::Trails;
+pub use vector_field::VectorField;
 
+mod boid_buffer;
+mod chunks;
+mod emitters;
 mod falling_sand;
 mod flock;
+mod flock3d;
 mod gol;
+mod heat;
+mod materials;
+mod metrics;
+mod obstacle;
+mod orca;
+mod rules;
 mod simulation;
+mod steering;
+mod trails;
+mod vector_field;
@@ -0,0 +1,260 @@
+use cgmath::prelude::*;
+use cgmath::Vector2;
+
+use crate::{
+    quadtree::{Bounds, Rectangle as Rect},
+    rendering::Instance,
+    utils::ScreenSpaceEncoder,
+};
+
+const COLOR: [f32; 4] = [0.16, 0.16, 0.16, 1.0];
+// height in pixels of the strips obstacles are filled with
+const STRIP_HEIGHT: f32 = 2.0;
+
+/// Static shapes boids steer around, in the same pixel space as the boids.
+#[derive(Debug, Clone)]
+pub enum Obstacle {
+    Circle {
+        x: f32,
+        y: f32,
+        radius: f32,
+    },
+    Rectangle {
+        x: f32,
+        y: f32,
+        width: f32,
+        height: f32,
+    },
+    /// Closed polygon, the last point connects back to the first.
+    Polygon(Vec<Vector2<f32>>),
+}
+
+impl Obstacle {
+    /// The same shape with a negative radius, width or height flipped so
+    /// the shape covers the area it was drawn over.
+    pub fn normalized(self) -> Self {
+        match self {
+            Obstacle::Circle { x, y, radius } => Obstacle::Circle {
+                x,
+                y,
+                radius: radius.abs(),
+            },
+            Obstacle::Rectangle {
+                x,
+                y,
+                width,
+                height,
+            } => Obstacle::Rectangle {
+                x: x.min(x + width),
+                y: y.min(y + height),
+                width: width.abs(),
+                height: height.abs(),
+            },
+            polygon => polygon,
+        }
+    }
+
+    pub fn bounds(&self) -> Bounds {
+        match self {
+            Obstacle::Circle { x, y, radius } => Bounds::Circle(*x, *y, *radius),
+            Obstacle::Rectangle {
+                x,
+                y,
+                width,
+                height,
+            } => Bounds::Rect(Rect {
+                x: *x,
+                y: *y,
+                width: *width,
+                height: *height,
+            }),
+            Obstacle::Polygon(points) => {
+                let mut min = Vector2::new(f32::MAX, f32::MAX);
+                let mut max = Vector2::new(f32::MIN, f32::MIN);
+                for point in points {
+                    min.x = min.x.min(point.x);
+                    min.y = min.y.min(point.y);
+                    max.x = max.x.max(point.x);
+                    max.y = max.y.max(point.y);
+                }
+                Bounds::Rect(Rect {
+                    x: min.x,
+                    y: min.y,
+                    width: max.x - min.x,
+                    height: max.y - min.y,
+                })
+            }
+        }
+    }
+
+    fn center(&self) -> Vector2<f32> {
+        let (x, y) = self.bounds().center();
+        Vector2::new(x, y)
+    }
+
+    fn edges(&self) -> Vec<(Vector2<f32>, Vector2<f32>)> {
+        let points = match self {
+            Obstacle::Circle { .. } => return Vec::new(),
+            Obstacle::Rectangle {
+                x,
+                y,
+                width,
+                height,
+            } => vec![
+                Vector2::new(*x, *y),
+                Vector2::new(x + width, *y),
+                Vector2::new(x + width, y + height),
+                Vector2::new(*x, y + height),
+            ],
+            Obstacle::Polygon(points) => points.clone(),
+        };
+        (0..points.len())
+            .map(|i| (points[i], points[(i + 1) % points.len()]))
+            .collect()
+    }
+
+    pub fn contains(&self, point: Vector2<f32>) -> bool {
+        match self {
+            Obstacle::Circle { x, y, radius } => {
+                (point.x - x).powi(2) + (point.y - y).powi(2) <= radius.powi(2)
+            }
+            // inside when the point falls in one of the runs at its height
+            _ => self
+                .spans(point.y)
+                .iter()
+                .any(|span| span.0 <= point.x && point.x <= span.1),
+        }
+    }
+
+    /// Horizontal runs of the shape on the line at `y`, left to right.
+    pub fn spans(&self, y: f32) -> Vec<(f32, f32)> {
+        if let Obstacle::Circle { x, y: cy, radius } = self {
+            let dy = y - cy;
+            if dy.abs() >= *radius {
+                return Vec::new();
+            }
+            let half = (radius.powi(2) - dy.powi(2)).sqrt();
+            return vec![(x - half, x + half)];
+        }
+
+        let mut crossings = Vec::new();
+        for (a, b) in self.edges() {
+            // half open so shared vertices only count once
+            if (a.y <= y && y < b.y) || (b.y <= y && y < a.y) {
+                crossings.push(a.x + (y - a.y) / (b.y - a.y) * (b.x - a.x));
+            }
+        }
+        crossings.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+        crossings
+            .chunks(2)
+            .filter(|pair| pair.len() == 2)
+            .map(|pair| (pair[0], pair[1]))
+            .collect()
+    }
+
+    /// Casts a ray from `origin` along the unit vector `direction`. Returns the
+    /// distance to the first hit within `length` and the surface normal there.
+    /// A ray starting inside the shape hits immediately and is pushed out from
+    /// the center.
+    pub fn raycast(
+        &self,
+        origin: Vector2<f32>,
+        direction: Vector2<f32>,
+        length: f32,
+    ) -> Option<(f32, Vector2<f32>)> {
+        if self.contains(origin) {
+            let away = origin - self.center();
+            let normal = if away.magnitude2() > 0.0 {
+                away.normalize()
+            } else {
+                -direction
+            };
+            return Some((0.0, normal));
+        }
+
+        if let Obstacle::Circle { x, y, radius } = self {
+            let center = Vector2::new(*x, *y);
+            let offset = origin - center;
+            let b = offset.dot(direction);
+            let c = offset.magnitude2() - radius.powi(2);
+            let discriminant = b * b - c;
+            if b > 0.0 || discriminant < 0.0 {
+                return None;
+            }
+            let distance = -b - discriminant.sqrt();
+            if distance > length {
+                return None;
+            }
+            let normal = (origin + direction * distance - center).normalize();
+            return Some((distance, normal));
+        }
+
+        let cross = |a: Vector2<f32>, b: Vector2<f32>| a.x * b.y - a.y * b.x;
+        let mut closest: Option<(f32, Vector2<f32>)> = None;
+        for (a, b) in self.edges() {
+            let edge = b - a;
+            let denominator = cross(direction, edge);
+            if denominator.abs() < f32::EPSILON {
+                continue;
+            }
+            let distance = cross(a - origin, edge) / denominator;
+            let along = cross(a - origin, direction) / denominator;
+            if !(0.0..=length).contains(&distance) || !(0.0..=1.0).contains(&along) {
+                continue;
+            }
+            if !matches!(closest, Some(hit) if hit.0 <= distance) {
+                let mut normal = Vector2::new(-edge.y, edge.x).normalize();
+                if normal.dot(direction) > 0.0 {
+                    normal = -normal;
+                }
+                closest = Some((distance, normal));
+            }
+        }
+        closest
+    }
+
+    /// Fills the shape with thin horizontal strips for the `Rectangle` renderer.
+    pub fn instances(&self, encoder: &ScreenSpaceEncoder) -> Vec<Instance> {
+        let aabb = self.bounds().aabb();
+        let mut instances = Vec::new();
+        let mut y = aabb.y;
+        while y < aabb.y + aabb.height {
+            for (start, end) in self.spans(y + STRIP_HEIGHT / 2.0) {
+                let (x0, y0) = encoder.encode(start, y);
+                let (x1, y1) = encoder.encode(end, y + STRIP_HEIGHT);
+                instances.push(Instance {
+                    x: x0,
+                    y: y0,
+                    width: x1 - x0,
+                    height: y1 - y0,
+                    angle: 0.0,
+                    color: COLOR,
+                });
+            }
+            y += STRIP_HEIGHT;
+        }
+        instances
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negative_rectangles_cover_the_area_they_were_drawn_over() {
+        let rectangle = Obstacle::Rectangle {
+            x: 50.0,
+            y: 40.0,
+            width: -20.0,
+            height: -10.0,
+        }
+        .normalized();
+        assert!(rectangle.contains(Vector2::new(40.0, 35.0)));
+        assert!(!rectangle.contains(Vector2::new(55.0, 35.0)));
+        assert_eq!(rectangle.bounds().aabb().width, 20.0);
+        assert!(rectangle
+            .raycast(Vector2::new(0.0, 35.0), Vector2::new(1.0, 0.0), 100.0)
+            .is_some());
+    }
+}
@@ -0,0 +1,365 @@
+use cgmath::prelude::*;
+use cgmath::Vector2;
+
+// Optimal reciprocal collision avoidance, after van den Berg et al. and the
+// RVO2 library. Every agent takes half the responsibility for avoiding each
+// neighbour, so when all of them use it nobody overlaps. Time is in steps.
+
+const EPSILON: f32 = 0.00001;
+
+#[derive(Debug, Copy, Clone)]
+pub struct OrcaAgent {
+    pub position: Vector2<f32>,
+    pub velocity: Vector2<f32>,
+    pub radius: f32,
+    pub max_speed: f32,
+}
+
+/// Half plane of allowed velocities, everything left of `direction` through
+/// `point`.
+#[derive(Debug, Copy, Clone)]
+pub struct Line {
+    pub point: Vector2<f32>,
+    pub direction: Vector2<f32>,
+}
+
+fn det(a: Vector2<f32>, b: Vector2<f32>) -> f32 {
+    a.x * b.y - a.y * b.x
+}
+
+// the velocities that keep `agent` clear of `other` for `time_horizon` steps
+fn half_plane(agent: &OrcaAgent, other: &OrcaAgent, time_horizon: f32) -> Line {
+    let relative_position = other.position - agent.position;
+    let relative_velocity = agent.velocity - other.velocity;
+    let distance2 = relative_position.magnitude2();
+    let combined_radius = agent.radius + other.radius;
+    let combined_radius2 = combined_radius * combined_radius;
+
+    let (direction, u) = if distance2 > combined_radius2 {
+        // vector from the cutoff center to the relative velocity
+        let w = relative_velocity - relative_position / time_horizon;
+        let w_length2 = w.magnitude2();
+        let dot = w.dot(relative_position);
+        if dot < 0.0 && dot * dot > combined_radius2 * w_length2 {
+            // closest to the cutoff circle
+            let w_length = w_length2.sqrt();
+            let unit_w = w / w_length;
+            (
+                Vector2::new(unit_w.y, -unit_w.x),
+                unit_w * (combined_radius / time_horizon - w_length),
+            )
+        } else {
+            // closest to one of the legs of the cone
+            let leg = (distance2 - combined_radius2).sqrt();
+            let direction = if det(relative_position, w) > 0.0 {
+                Vector2::new(
+                    relative_position.x * leg - relative_position.y * combined_radius,
+                    relative_position.x * combined_radius + relative_position.y * leg,
+                ) / distance2
+            } else {
+                -Vector2::new(
+                    relative_position.x * leg + relative_position.y * combined_radius,
+                    -relative_position.x * combined_radius + relative_position.y * leg,
+                ) / distance2
+            };
+            let u = direction * relative_velocity.dot(direction) - relative_velocity;
+            (direction, u)
+        }
+    } else {
+        // already overlapping, get apart within the next step
+        let w = relative_velocity - relative_position;
+        let w_length = w.magnitude();
+        let unit_w = if w_length > 0.0 {
+            w / w_length
+        } else {
+            Vector2::new(1.0, 0.0)
+        };
+        (
+            Vector2::new(unit_w.y, -unit_w.x),
+            unit_w * (combined_radius - w_length),
+        )
+    };
+
+    Line {
+        point: agent.velocity + u * 0.5,
+        direction,
+    }
+}
+
+// best velocity on line `index` that satisfies every earlier line
+fn linear_program1(
+    lines: &[Line],
+    index: usize,
+    radius: f32,
+    optimal: Vector2<f32>,
+    optimize_direction: bool,
+) -> Option<Vector2<f32>> {
+    let line = lines[index];
+    let dot = line.point.dot(line.direction);
+    let discriminant = dot * dot + radius * radius - line.point.magnitude2();
+    if discriminant < 0.0 {
+        // the max speed circle misses the line entirely
+        return None;
+    }
+    let root = discriminant.sqrt();
+    let mut left = -dot - root;
+    let mut right = -dot + root;
+
+    for other in &lines[..index] {
+        let denominator = det(line.direction, other.direction);
+        let numerator = det(other.direction, line.point - other.point);
+        if denominator.abs() <= EPSILON {
+            // parallel lines
+            if numerator < 0.0 {
+                return None;
+            }
+            continue;
+        }
+        let t = numerator / denominator;
+        if denominator >= 0.0 {
+            right = right.min(t);
+        } else {
+            left = left.max(t);
+        }
+        if left > right {
+            return None;
+        }
+    }
+
+    let t = if optimize_direction {
+        if optimal.dot(line.direction) > 0.0 {
+            right
+        } else {
+            left
+        }
+    } else {
+        line.direction
+            .dot(optimal - line.point)
+            .max(left)
+            .min(right)
+    };
+    Some(line.point + line.direction * t)
+}
+
+// closest velocity to `optimal` within `radius` satisfying every line. Returns
+// how many lines were satisfied, all of them unless the program is infeasible.
+fn linear_program2(
+    lines: &[Line],
+    radius: f32,
+    optimal: Vector2<f32>,
+    optimize_direction: bool,
+    result: &mut Vector2<f32>,
+) -> usize {
+    *result = if optimize_direction {
+        optimal * radius
+    } else if optimal.magnitude2() > radius * radius {
+        optimal.normalize() * radius
+    } else {
+        optimal
+    };
+
+    for index in 0..lines.len() {
+        if det(lines[index].direction, lines[index].point - *result) > 0.0 {
+            match linear_program1(lines, index, radius, optimal, optimize_direction) {
+                Some(velocity) => *result = velocity,
+                None => return index,
+            }
+        }
+    }
+    lines.len()
+}
+
+// when the lines can't all hold, minimize the largest violation instead
+fn linear_program3(lines: &[Line], begin: usize, radius: f32, result: &mut Vector2<f32>) {
+    let mut distance = 0.0;
+    let mut projected = Vec::new();
+    for index in begin..lines.len() {
+        let line = lines[index];
+        if det(line.direction, line.point - *result) <= distance {
+            continue;
+        }
+
+        projected.clear();
+        for other in &lines[..index] {
+            let determinant = det(line.direction, other.direction);
+            let point = if determinant.abs() <= EPSILON {
+                if line.direction.dot(other.direction) > 0.0 {
+                    // same direction, the other line adds nothing
+                    continue;
+                }
+                (line.point + other.point) * 0.5
+            } else {
+                line.point
+                    + line.direction
+                        * (det(other.direction, line.point - other.point) / determinant)
+            };
+            projected.push(Line {
+                point,
+                direction: (other.direction - line.direction).normalize(),
+            });
+        }
+
+        let previous = *result;
+        let optimal = Vector2::new(-line.direction.y, line.direction.x);
+        if linear_program2(&projected, radius, optimal, true, result) < projected.len() {
+            // can only happen through rounding, keep the last good answer
+            *result = previous;
+        }
+        distance = det(line.direction, line.point - *result);
+    }
+}
+
+/// The velocity closest to `preferred` that keeps `agent` from running into
+/// any of `others` within `time_horizon` steps, assuming they do the same.
+/// `lines` is scratch space.
+pub fn safe_velocity(
+    agent: &OrcaAgent,
+    preferred: Vector2<f32>,
+    others: &[OrcaAgent],
+    time_horizon: f32,
+    lines: &mut Vec<Line>,
+) -> Vector2<f32> {
+    lines.clear();
+    lines.extend(
+        others
+            .iter()
+            .map(|other| half_plane(agent, other, time_horizon.max(1.0))),
+    );
+
+    let mut velocity = Vector2::new(0.0, 0.0);
+    let satisfied = linear_program2(lines, agent.max_speed, preferred, false, &mut velocity);
+    if satisfied < lines.len() {
+        linear_program3(lines, satisfied, agent.max_speed, &mut velocity);
+    }
+    velocity
+}
+
+/// Pushes apart the `pairs` of agents that overlap, `offset(a, b)` being the
+/// vector from position `a` to position `b`, and `constrain` moving a pushed
+/// position back to where agents are allowed, like inside a wall. The solver
+/// alone can't avoid every overlap when a crowd is too dense for a collision
+/// free answer to exist.
+pub fn separate<F, G>(agents: &mut [OrcaAgent], pairs: &[(usize, usize)], offset: F, constrain: G)
+where
+    F: Fn(Vector2<f32>, Vector2<f32>) -> Vector2<f32>,
+    G: Fn(Vector2<f32>) -> Vector2<f32>,
+{
+    // each pass can push agents into others, stops as soon as none overlap
+    for _ in 0..256 {
+        let mut moved = false;
+        for &(a, b) in pairs {
+            let between = offset(agents[a].position, agents[b].position);
+            let distance = between.magnitude();
+            let overlap = agents[a].radius + agents[b].radius - distance;
+            if overlap <= EPSILON {
+                continue;
+            }
+            let normal = if distance > 0.0 {
+                between / distance
+            } else {
+                Vector2::new(1.0, 0.0)
+            };
+            // a little past touching, which leaves a packed crowd room to
+            // settle in fewer passes, and however far `constrain` holds one
+            // back the other goes instead
+            let push = overlap + 0.01 * (agents[a].radius + agents[b].radius);
+            let before = agents[a].position;
+            agents[a].position = constrain(before - normal * (push / 2.0));
+            let pushed = offset(agents[a].position, before).dot(normal);
+            agents[b].position = constrain(agents[b].position + normal * (push - pushed));
+            moved = true;
+        }
+        if !moved {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // moves every agent one step towards its preferred velocity, checking
+    // every pair
+    fn step_agents(agents: &mut [OrcaAgent], preferred: &[Vector2<f32>], time_horizon: f32) {
+        let mut lines = Vec::new();
+        let mut others = Vec::new();
+        let velocities: Vec<Vector2<f32>> = (0..agents.len())
+            .map(|index| {
+                others.clear();
+                others.extend(
+                    agents
+                        .iter()
+                        .enumerate()
+                        .filter(|&(other, _)| other != index)
+                        .map(|(_, other)| *other),
+                );
+                safe_velocity(
+                    &agents[index],
+                    preferred[index],
+                    &others,
+                    time_horizon,
+                    &mut lines,
+                )
+            })
+            .collect();
+        for (agent, velocity) in agents.iter_mut().zip(velocities) {
+            agent.velocity = velocity;
+            agent.position += velocity;
+        }
+        let pairs: Vec<(usize, usize)> = (0..agents.len())
+            .flat_map(|a| (a + 1..agents.len()).map(move |b| (a, b)))
+            .collect();
+        separate(agents, &pairs, |a, b| b - a, |position| position);
+    }
+
+    fn overlaps(agents: &[OrcaAgent]) -> usize {
+        let mut count = 0;
+        for a in 0..agents.len() {
+            for b in a + 1..agents.len() {
+                let distance = (agents[b].position - agents[a].position).magnitude();
+                if distance < agents[a].radius + agents[b].radius - 0.001 {
+                    count += 1;
+                }
+            }
+        }
+        count
+    }
+
+    #[test]
+    fn crossing_crowds_never_overlap() {
+        // two rows walking through each other, plus a few stacked on one spot
+        let mut agents = Vec::new();
+        let mut preferred = Vec::new();
+        for row in 0..2 {
+            for column in 0..8 {
+                let side = if row == 0 { -1.0 } else { 1.0 };
+                agents.push(OrcaAgent {
+                    position: Vector2::new(side * 30.0, column as f32 * 3.0),
+                    velocity: Vector2::new(0.0, 0.0),
+                    radius: 1.0,
+                    max_speed: 1.0,
+                });
+                preferred.push(Vector2::new(-side, 0.0));
+            }
+        }
+        for _ in 0..4 {
+            agents.push(OrcaAgent {
+                position: Vector2::new(0.0, 10.0),
+                velocity: Vector2::new(0.0, 0.0),
+                radius: 1.0,
+                max_speed: 1.0,
+            });
+            preferred.push(Vector2::new(0.0, 0.0));
+        }
+
+        for _ in 0..80 {
+            step_agents(&mut agents, &preferred, 10.0);
+            assert_eq!(overlaps(&agents), 0);
+        }
+        // everyone made it across
+        for (agent, preferred) in agents.iter().zip(&preferred) {
+            assert!(agent.position.x * preferred.x >= 0.0);
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use cgmath::prelude::*;
+
+use crate::simulations::{Interaction, Neighbour};
+use crate::utils::{limit, set_magnitude};
+
+// The three classic flocking rules, written for any vector space so the 2D
+// and 3D flocks share them. Each returns a steering force limited to `force`.
+
+// turn the average of `sum` over `total` neighbours into a steering force
+fn steer<V: InnerSpace<Scalar = f32>>(
+    velocity: V,
+    sum: V,
+    total: usize,
+    max_speed: f32,
+    force: f32,
+) -> V {
+    if total == 0 || sum == V::zero() {
+        return sum;
+    }
+    let desired = set_magnitude(sum / total as f32, max_speed);
+    limit(desired - velocity, force)
+}
+
+/// Steer towards the average heading of flockmates within `radius`.
+pub fn align<V: InnerSpace<Scalar = f32>>(
+    velocity: V,
+    neighbours: &[Neighbour<V>],
+    radius: f32,
+    max_speed: f32,
+    force: f32,
+) -> V {
+    let mut sum = V::zero();
+    let mut total = 0;
+    for other in neighbours {
+        if other.interaction == Interaction::Flock && other.distance < radius {
+            sum = sum + other.velocity;
+            total += 1;
+        }
+    }
+    steer(velocity, sum, total, max_speed, force)
+}
+
+/// Steer towards the center of flockmates within `radius`.
+pub fn cohesion<V: InnerSpace<Scalar = f32>>(
+    velocity: V,
+    neighbours: &[Neighbour<V>],
+    radius: f32,
+    max_speed: f32,
+    force: f32,
+) -> V {
+    let mut sum = V::zero();
+    let mut total = 0;
+    for other in neighbours {
+        if other.interaction == Interaction::Flock && other.distance < radius {
+            // offsets are relative, so their mean already points at the center
+            sum = sum + other.offset;
+            total += 1;
+        }
+    }
+    steer(velocity, sum, total, max_speed, force)
+}
+
+/// Steer away from flockmates within `radius`.
+pub fn seperation<V: InnerSpace<Scalar = f32>>(
+    velocity: V,
+    neighbours: &[Neighbour<V>],
+    radius: f32,
+    max_speed: f32,
+    force: f32,
+) -> V {
+    let mut sum = V::zero();
+    let mut total = 0;
+    for other in neighbours {
+        if other.interaction == Interaction::Flock
+            && other.distance < radius
+            && other.distance > 0.0
+        {
+            sum = sum - other.offset / other.distance;
+            total += 1;
+        }
+    }
+    steer(velocity, sum, total, max_speed, force)
+}
@@ -0,0 +1,358 @@
+use cgmath::prelude::*;
+use cgmath::Vector2;
+
+use crate::utils::{limit, set_magnitude};
+
+/// The kinematic state a steering behaviour works from. Every behaviour
+/// returns a force already limited to `max_force`.
+#[derive(Debug, Copy, Clone)]
+pub struct Agent {
+    pub position: Vector2<f32>,
+    pub velocity: Vector2<f32>,
+    pub max_speed: f32,
+    pub max_force: f32,
+}
+
+#[derive(Debug, Clone)]
+pub struct Path {
+    pub points: Vec<Vector2<f32>>,
+    /// How far agents may stray from the path, and how close they have to
+    /// come to a waypoint to have reached it.
+    pub radius: f32,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Wander {
+    /// Distance of the wander circle in front of the agent.
+    pub distance: f32,
+    pub radius: f32,
+    /// Largest change of the wander angle per step, in radians.
+    pub jitter: f32,
+}
+
+// Reynolds' steering = desired velocity - current velocity
+fn steer(agent: &Agent, desired: Vector2<f32>) -> Vector2<f32> {
+    limit(desired - agent.velocity, agent.max_force)
+}
+
+/// Head straight for `target` at full speed.
+pub fn seek(agent: &Agent, target: Vector2<f32>) -> Vector2<f32> {
+    let desired = set_magnitude(target - agent.position, agent.max_speed);
+    steer(agent, desired)
+}
+
+/// Head straight away from `threat` at full speed.
+pub fn flee(agent: &Agent, threat: Vector2<f32>) -> Vector2<f32> {
+    let desired = set_magnitude(agent.position - threat, agent.max_speed);
+    steer(agent, desired)
+}
+
+/// Seek that slows down linearly inside `slowing_radius` and stops on `target`.
+pub fn arrive(agent: &Agent, target: Vector2<f32>, slowing_radius: f32) -> Vector2<f32> {
+    let offset = target - agent.position;
+    let distance = offset.magnitude();
+    let speed = if distance < slowing_radius {
+        agent.max_speed * distance / slowing_radius
+    } else {
+        agent.max_speed
+    };
+    steer(agent, set_magnitude(offset, speed))
+}
+
+/// Random walk that stays smooth by seeking a point on a circle ahead of the
+/// agent. `angle` is the agent's wander state and `sample` a random number in
+/// -1..1, passed in so the behaviour stays deterministic.
+pub fn wander(agent: &Agent, angle: &mut f32, sample: f32, params: &Wander) -> Vector2<f32> {
+    *angle += sample * params.jitter;
+    let heading = if agent.velocity.magnitude2() > 0.0 {
+        agent.velocity.normalize()
+    } else {
+        Vector2::new(1.0, 0.0)
+    };
+    let center = agent.position + heading * params.distance;
+    let heading_angle = heading.y.atan2(heading.x);
+    let offset = Vector2::new(
+        (heading_angle + *angle).cos(),
+        (heading_angle + *angle).sin(),
+    ) * params.radius;
+    seek(agent, center + offset)
+}
+
+// where a moving target will be by the time the agent could get there
+fn predict(agent: &Agent, position: Vector2<f32>, velocity: Vector2<f32>) -> Vector2<f32> {
+    let distance = (position - agent.position).magnitude();
+    let time = if agent.max_speed > 0.0 {
+        distance / agent.max_speed
+    } else {
+        0.0
+    };
+    position + velocity * time
+}
+
+/// Seek where a moving target is heading.
+pub fn pursue(agent: &Agent, position: Vector2<f32>, velocity: Vector2<f32>) -> Vector2<f32> {
+    seek(agent, predict(agent, position, velocity))
+}
+
+/// Flee from where a moving threat is heading.
+pub fn evade(agent: &Agent, position: Vector2<f32>, velocity: Vector2<f32>) -> Vector2<f32> {
+    flee(agent, predict(agent, position, velocity))
+}
+
+fn closest_on_segment(point: Vector2<f32>, a: Vector2<f32>, b: Vector2<f32>) -> Vector2<f32> {
+    let segment = b - a;
+    let length = segment.magnitude2();
+    if length == 0.0 {
+        return a;
+    }
+    let t = ((point - a).dot(segment) / length).clamp(0.0, 1.0);
+    a + segment * t
+}
+
+/// Head from waypoint to waypoint along the path, steering back towards a
+/// point a little further along it whenever the predicted position strays
+/// outside its radius. The waypoint at the end of the segment nearest the
+/// predicted position is the next one until the agent comes within the
+/// radius of it, and the agent slows to a stop on the last.
+pub fn follow_path(agent: &Agent, path: &Path) -> Vector2<f32> {
+    let lookahead = agent.max_speed * 4.0;
+    let last = match path.points.len() {
+        0 => return Vector2::new(0.0, 0.0),
+        len => len - 1,
+    };
+    if last == 0 {
+        return arrive(agent, path.points[0], lookahead);
+    }
+
+    let future = agent.position + set_magnitude(agent.velocity, lookahead);
+    let mut closest: Option<(f32, usize, Vector2<f32>)> = None;
+    for (index, segment) in path.points.windows(2).enumerate() {
+        let normal = closest_on_segment(future, segment[0], segment[1]);
+        let distance = (future - normal).magnitude();
+        if !matches!(closest, Some(closest) if closest.0 <= distance) {
+            // aim a bit down the segment so agents make progress along it
+            let direction = set_magnitude(segment[1] - segment[0], lookahead / 2.0);
+            closest = Some((distance, index + 1, normal + direction));
+        }
+    }
+
+    let (distance, mut next, target) = closest.unwrap();
+    if (path.points[next] - agent.position).magnitude() <= path.radius {
+        next = (next + 1).min(last);
+    } else if distance > path.radius {
+        return seek(agent, target);
+    }
+    if next == last {
+        arrive(agent, path.points[last], lookahead)
+    } else {
+        seek(agent, path.points[next])
+    }
+}
+
+/// Arrive at a point `distance` behind the leader and get out of the way when
+/// standing in front of it.
+pub fn follow_leader(
+    agent: &Agent,
+    position: Vector2<f32>,
+    velocity: Vector2<f32>,
+    distance: f32,
+) -> Vector2<f32> {
+    let heading = set_magnitude(velocity, distance);
+    let behind = position - heading;
+    let ahead = position + heading;
+    let mut force = arrive(agent, behind, distance);
+    if (agent.position - ahead).magnitude() < distance
+        || (agent.position - position).magnitude() < distance / 2.0
+    {
+        force += evade(agent, position, velocity);
+    }
+    limit(force, agent.max_force)
+}
+
+/// A behaviour the flock applies to every boid. Targets given as an index
+/// refer to a boid in the flock, which doesn't steer towards itself.
+#[derive(Debug, Clone)]
+pub enum Behaviour {
+    Seek(Vector2<f32>),
+    Flee(Vector2<f32>),
+    Arrive {
+        target: Vector2<f32>,
+        slowing_radius: f32,
+    },
+    Wander(Wander),
+    Pursue(usize),
+    Evade(usize),
+    FollowPath(Path),
+    FollowLeader {
+        leader: usize,
+        distance: f32,
+    },
+}
+
+impl Behaviour {
+    /// The index of the boid the behaviour steers relative to, if any.
+    pub fn target_mut(&mut self) -> Option<&mut usize> {
+        match self {
+            Behaviour::Pursue(index) | Behaviour::Evade(index) => Some(index),
+            Behaviour::FollowLeader { leader, .. } => Some(leader),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn agent(x: f32, y: f32, vx: f32, vy: f32) -> Agent {
+        Agent {
+            position: Vector2::new(x, y),
+            velocity: Vector2::new(vx, vy),
+            max_speed: 2.0,
+            max_force: 0.5,
+        }
+    }
+
+    fn close(a: Vector2<f32>, b: Vector2<f32>) -> bool {
+        (a - b).magnitude() < 1e-4
+    }
+
+    #[test]
+    fn seek_and_flee_push_along_the_line_to_the_target() {
+        let agent = agent(0.0, 0.0, 0.0, 0.0);
+        let target = Vector2::new(10.0, 0.0);
+        assert!(close(seek(&agent, target), Vector2::new(0.5, 0.0)));
+        assert!(close(flee(&agent, target), Vector2::new(-0.5, 0.0)));
+    }
+
+    #[test]
+    fn forces_never_exceed_max_force() {
+        let agent = agent(0.0, 0.0, -2.0, 0.0);
+        let target = Vector2::new(10.0, 3.0);
+        assert!(seek(&agent, target).magnitude() <= agent.max_force + 1e-5);
+        assert!(flee(&agent, target).magnitude() <= agent.max_force + 1e-5);
+        assert!(arrive(&agent, target, 5.0).magnitude() <= agent.max_force + 1e-5);
+    }
+
+    #[test]
+    fn arrive_slows_down_and_stops_on_the_target() {
+        // at the target it brakes against its own velocity
+        let moving = agent(5.0, 0.0, 0.4, 0.0);
+        assert!(close(
+            arrive(&moving, Vector2::new(5.0, 0.0), 10.0),
+            Vector2::new(-0.4, 0.0)
+        ));
+        // halfway into the slowing radius it wants half the speed
+        let still = Agent {
+            max_force: 10.0,
+            ..agent(0.0, 0.0, 0.0, 0.0)
+        };
+        assert!(close(
+            arrive(&still, Vector2::new(5.0, 0.0), 10.0),
+            Vector2::new(1.0, 0.0)
+        ));
+        assert!(close(
+            arrive(&still, Vector2::new(50.0, 0.0), 10.0),
+            Vector2::new(2.0, 0.0)
+        ));
+    }
+
+    #[test]
+    fn pursue_and_evade_lead_a_moving_target() {
+        let agent = Agent {
+            max_force: 10.0,
+            ..agent(0.0, 0.0, 0.0, 0.0)
+        };
+        // 10 away at speed 2 takes 5 steps, by when the target moved 5 up
+        let (position, velocity) = (Vector2::new(10.0, 0.0), Vector2::new(0.0, 1.0));
+        let lead = Vector2::new(10.0, 5.0).normalize() * agent.max_speed;
+        assert!(close(pursue(&agent, position, velocity), lead));
+        assert!(close(evade(&agent, position, velocity), -lead));
+        // a still target is just sought
+        let still = Vector2::new(0.0, 0.0);
+        assert!(close(
+            pursue(&agent, position, still),
+            seek(&agent, position)
+        ));
+    }
+
+    #[test]
+    fn wander_seeks_a_point_on_the_circle_ahead() {
+        let agent = Agent {
+            max_force: 10.0,
+            ..agent(0.0, 0.0, 1.0, 0.0)
+        };
+        let params = Wander {
+            distance: 4.0,
+            radius: 3.0,
+            jitter: 0.5,
+        };
+        let mut angle = std::f32::consts::FRAC_PI_2;
+        let force = wander(&agent, &mut angle, 0.0, &params);
+        assert!(close(force, seek(&agent, Vector2::new(4.0, 3.0))));
+
+        // the angle drifts by at most the jitter
+        wander(&agent, &mut angle, -1.0, &params);
+        assert!((angle - (std::f32::consts::FRAC_PI_2 - 0.5)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn followers_fall_in_behind_the_leader() {
+        let (leader, velocity) = (Vector2::new(0.0, 0.0), Vector2::new(1.0, 0.0));
+        // a follower far off to the left heads for the spot behind the leader
+        let behind = agent(-20.0, 0.0, 0.0, 0.0);
+        let force = follow_leader(&behind, leader, velocity, 5.0);
+        assert!(close(force, arrive(&behind, Vector2::new(-5.0, 0.0), 5.0)));
+        assert!(force.x > 0.0);
+
+        // one standing in front of the leader also gets out of its way
+        let ahead = agent(4.0, 1.0, 0.0, 0.0);
+        let force = follow_leader(&ahead, leader, velocity, 5.0);
+        assert!(force.magnitude() <= ahead.max_force + 1e-5);
+        assert!(force.y > 0.0);
+    }
+
+    // moves the agent along the force a step, the way boids are
+    fn step(agent: &mut Agent, force: Vector2<f32>) {
+        agent.velocity = limit(agent.velocity + force, agent.max_speed);
+        agent.position += agent.velocity;
+    }
+
+    #[test]
+    fn path_followers_go_waypoint_to_waypoint_and_stop_at_the_end() {
+        let path = Path {
+            points: vec![
+                Vector2::new(0.0, 0.0),
+                Vector2::new(50.0, 0.0),
+                Vector2::new(50.0, 50.0),
+            ],
+            radius: 3.0,
+        };
+        // short of the corner it heads for the corner
+        let before = agent(40.0, 0.0, 2.0, 0.0);
+        assert!(close(
+            follow_path(&before, &path),
+            seek(&before, Vector2::new(50.0, 0.0))
+        ));
+        // within the radius of the corner it heads on for the next waypoint
+        let at = agent(48.0, 0.0, 2.0, 0.0);
+        assert!(close(
+            follow_path(&at, &path),
+            arrive(&at, Vector2::new(50.0, 50.0), 8.0)
+        ));
+        assert!(follow_path(&at, &path).y > 0.0);
+
+        let mut walker = agent(0.0, 0.0, 0.0, 0.0);
+        let mut turned = false;
+        for _ in 0..300 {
+            let force = follow_path(&walker, &path);
+            step(&mut walker, force);
+            turned |= (walker.position - Vector2::new(50.0, 0.0)).magnitude() <= path.radius;
+        }
+        // and comes to rest on the last one
+        assert!(turned);
+        assert!((walker.position - Vector2::new(50.0, 50.0)).magnitude() < 1.0);
+        assert!(walker.velocity.magnitude() < 0.1);
+        assert!(close(follow_path(&walker, &path), -walker.velocity));
+    }
+}
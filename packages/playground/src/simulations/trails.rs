@@ -0,0 +1,72 @@
+use cgmath::Vector2;
+
+/// The last few positions of every boid. Each boid gets a fixed size ring
+/// buffer, stored back to back, and all of them share the write head since
+/// every boid records one position per step.
+#[derive(Debug, Clone, Default)]
+pub struct Trails {
+    length: usize,
+    // slot the next step is written to
+    head: usize,
+    points: Vec<Vector2<f32>>,
+    // how many slots of each boid hold a position so far
+    filled: Vec<usize>,
+}
+
+impl Trails {
+    pub fn length(&self) -> usize {
+        self.length
+    }
+
+    /// Positions kept per boid, 0 turns trails off. Drops the current history.
+    pub fn set_length(&mut self, length: usize) {
+        let boids = self.filled.len();
+        self.length = length;
+        self.head = 0;
+        self.points = vec![Vector2::new(0.0, 0.0); boids * length];
+        self.filled = vec![0; boids];
+    }
+
+    pub fn push_boid(&mut self) {
+        self.points
+            .resize(self.points.len() + self.length, Vector2::new(0.0, 0.0));
+        self.filled.push(0);
+    }
+
+    pub fn truncate(&mut self, boids: usize) {
+        self.points.truncate(boids * self.length);
+        self.filled.truncate(boids);
+    }
+
+    /// Keeps the trails of the boids whose entry in `keep` is true.
+    pub fn retain(&mut self, keep: &[bool]) {
+        let mut kept = 0;
+        for (boid, _) in keep.iter().enumerate().filter(|&(_, &keep)| keep) {
+            let from = boid * self.length;
+            self.points
+                .copy_within(from..from + self.length, kept * self.length);
+            self.filled[kept] = self.filled[boid];
+            kept += 1;
+        }
+        self.truncate(kept);
+    }
+
+    /// Records one step, `positions` holds every boid in order.
+    pub fn record(&mut self, positions: &[Vector2<f32>]) {
+        if self.length == 0 {
+            return;
+        }
+        for (boid, &position) in positions.iter().enumerate() {
+            self.points[boid * self.length + self.head] = position;
+            self.filled[boid] = (self.filled[boid] + 1).min(self.length);
+        }
+        self.head = (self.head + 1) % self.length;
+    }
+
+    /// A boid's recorded positions, newest first.
+    pub fn points(&self, boid: usize) -> impl Iterator<Item = Vector2<f32>> + '_ {
+        let start = boid * self.length;
+        let newest = self.head + self.length;
+        (1..=self.filled[boid]).map(move |age| self.points[start + (newest - age) % self.length])
+    }
+}
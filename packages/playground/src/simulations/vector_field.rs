@@ -0,0 +1,145 @@
+use cgmath::prelude::*;
+use cgmath::Vector2;
+
+/// A force that depends on where a boid is and when, in the same pixel space
+/// as the boids. `time` counts steps.
+#[derive(Debug, Clone)]
+pub enum VectorField {
+    /// The same wind everywhere. `gust` scales a slow random swell in its
+    /// strength, 0 keeps it steady.
+    Uniform { wind: Vector2<f32>, gust: f32 },
+    /// Swirls counter clockwise around `center`, strongest at the center and
+    /// gone at `radius`. A negative strength swirls clockwise.
+    Vortex {
+        center: Vector2<f32>,
+        strength: f32,
+        radius: f32,
+    },
+    /// Smooth random directions. `scale` is the size of a feature in pixels
+    /// and `speed` how fast the pattern changes per step.
+    Noise {
+        scale: f32,
+        strength: f32,
+        speed: f32,
+    },
+    /// Vectors painted onto cells of `cell` pixels, blended between cells.
+    Grid {
+        cell: f32,
+        columns: usize,
+        rows: usize,
+        vectors: Vec<Vector2<f32>>,
+    },
+}
+
+impl VectorField {
+    /// An empty grid covering a `width` by `height` world.
+    pub fn grid(width: u32, height: u32, cell: f32) -> Self {
+        let cell = cell.max(1.0);
+        let columns = (width as f32 / cell).ceil() as usize + 1;
+        let rows = (height as f32 / cell).ceil() as usize + 1;
+        VectorField::Grid {
+            cell,
+            columns,
+            rows,
+            vectors: vec![Vector2::new(0.0, 0.0); columns * rows],
+        }
+    }
+
+    pub fn sample(&self, position: Vector2<f32>, time: f32) -> Vector2<f32> {
+        match self {
+            VectorField::Uniform { wind, gust } => {
+                *wind * (1.0 + gust * (noise(time * 0.01, 0.0, 0.0) * 2.0 - 1.0))
+            }
+            VectorField::Vortex {
+                center,
+                strength,
+                radius,
+            } => {
+                let offset = position - *center;
+                let distance = offset.magnitude();
+                if distance >= *radius || distance == 0.0 {
+                    return Vector2::new(0.0, 0.0);
+                }
+                Vector2::new(-offset.y, offset.x) / distance * *strength * (1.0 - distance / radius)
+            }
+            VectorField::Noise {
+                scale,
+                strength,
+                speed,
+            } => {
+                let scale = scale.max(1.0);
+                let angle = noise(position.x / scale, position.y / scale, time * speed)
+                    * std::f32::consts::PI
+                    * 4.0;
+                Vector2::new(angle.cos(), angle.sin()) * *strength
+            }
+            VectorField::Grid {
+                cell,
+                columns,
+                rows,
+                vectors,
+            } => {
+                let x = (position.x / cell).max(0.0).min((columns - 1) as f32);
+                let y = (position.y / cell).max(0.0).min((rows - 1) as f32);
+                let (x0, y0) = (x.floor() as usize, y.floor() as usize);
+                let (x1, y1) = ((x0 + 1).min(columns - 1), (y0 + 1).min(rows - 1));
+                let (tx, ty) = (x - x0 as f32, y - y0 as f32);
+                let at = |x: usize, y: usize| vectors[y * columns + x];
+                let bottom = at(x0, y0).lerp(at(x1, y0), tx);
+                let top = at(x0, y1).lerp(at(x1, y1), tx);
+                bottom.lerp(top, ty)
+            }
+        }
+    }
+
+    /// Paints `vector` onto every cell within `radius` of `position`, fading
+    /// towards the edge of the brush. Only grids can be painted.
+    pub fn paint(&mut self, position: Vector2<f32>, vector: Vector2<f32>, radius: f32) -> bool {
+        let (cell, columns, rows, vectors) = match self {
+            VectorField::Grid {
+                cell,
+                columns,
+                rows,
+                vectors,
+            } => (*cell, *columns, *rows, vectors),
+            _ => return false,
+        };
+        for row in 0..rows {
+            for column in 0..columns {
+                let point = Vector2::new(column as f32, row as f32) * cell;
+                let distance = point.distance(position);
+                if distance <= radius {
+                    let strength = 1.0 - distance / radius.max(f32::EPSILON);
+                    let current = &mut vectors[row * columns + column];
+                    *current = current.lerp(vector, strength);
+                }
+            }
+        }
+        true
+    }
+}
+
+// integer lattice hash to 0..1
+fn hash(x: i32, y: i32, z: i32) -> f32 {
+    let mut h = (x as u32).wrapping_mul(0x8da6_b343)
+        ^ (y as u32).wrapping_mul(0xd816_3841)
+        ^ (z as u32).wrapping_mul(0xcb1a_b31f);
+    h = (h ^ (h >> 13)).wrapping_mul(0x5bd1_e995);
+    h ^= h >> 15;
+    h as f32 / u32::MAX as f32
+}
+
+// smoothly interpolated value noise in 0..1
+fn noise(x: f32, y: f32, z: f32) -> f32 {
+    let (x0, y0, z0) = (x.floor(), y.floor(), z.floor());
+    let fade = |t: f32| t * t * (3.0 - 2.0 * t);
+    let (tx, ty, tz) = (fade(x - x0), fade(y - y0), fade(z - z0));
+    let (x0, y0, z0) = (x0 as i32, y0 as i32, z0 as i32);
+    let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+    let layer = |z: i32| {
+        let bottom = lerp(hash(x0, y0, z), hash(x0 + 1, y0, z), tx);
+        let top = lerp(hash(x0, y0 + 1, z), hash(x0 + 1, y0 + 1, z), tx);
+        lerp(bottom, top, ty)
+    };
+    lerp(layer(z0), layer(z0 + 1), tz)
+}
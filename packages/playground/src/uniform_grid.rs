@@ -0,0 +1,91 @@
+use cgmath::Vector3;
+
+/// Buckets points of a box into cubic cells so neighbours can be found by
+/// looking at the cells around a point. Rebuilt from scratch every step with a
+/// counting sort, so it never allocates once it has grown to size.
+#[derive(Debug, Clone)]
+pub struct UniformGrid {
+    cell: f32,
+    dimensions: (usize, usize, usize),
+    // entries[starts[c]..starts[c + 1]] are the points in cell c
+    starts: Vec<usize>,
+    entries: Vec<usize>,
+}
+
+impl UniformGrid {
+    /// A grid over the box from the origin to `size`. `cell` should be at
+    /// least the largest radius that will be queried.
+    pub fn new(size: Vector3<f32>, cell: f32) -> Self {
+        let cell = cell.max(1.0);
+        let cells = |length: f32| ((length / cell).ceil() as usize).max(1);
+        let dimensions = (cells(size.x), cells(size.y), cells(size.z));
+        Self {
+            cell,
+            dimensions,
+            starts: vec![0; dimensions.0 * dimensions.1 * dimensions.2 + 1],
+            entries: Vec::new(),
+        }
+    }
+
+    // cell coordinates of a point, clamped into the grid
+    fn coordinates(&self, position: Vector3<f32>) -> (usize, usize, usize) {
+        let clamp =
+            |value: f32, cells: usize| ((value / self.cell).max(0.0) as usize).min(cells - 1);
+        (
+            clamp(position.x, self.dimensions.0),
+            clamp(position.y, self.dimensions.1),
+            clamp(position.z, self.dimensions.2),
+        )
+    }
+
+    fn index(&self, (x, y, z): (usize, usize, usize)) -> usize {
+        (z * self.dimensions.1 + y) * self.dimensions.0 + x
+    }
+
+    /// Replaces the contents with `positions`, indexed by their position in
+    /// the slice.
+    pub fn rebuild(&mut self, positions: &[Vector3<f32>]) {
+        for start in self.starts.iter_mut() {
+            *start = 0;
+        }
+        // count, then turn the counts into the end of each cell's run
+        for &position in positions {
+            let cell = self.index(self.coordinates(position));
+            self.starts[cell + 1] += 1;
+        }
+        for cell in 1..self.starts.len() {
+            self.starts[cell] += self.starts[cell - 1];
+        }
+        self.entries.clear();
+        self.entries.resize(positions.len(), 0);
+        // fill each run back to front so the starts end up where they began
+        for (index, &position) in positions.iter().enumerate().rev() {
+            let cell = self.index(self.coordinates(position)) + 1;
+            self.starts[cell] -= 1;
+            self.entries[self.starts[cell]] = index;
+        }
+        // every run was filled from its end, shift the starts back by one cell
+        self.starts.rotate_left(1);
+        let last = self.starts.len() - 1;
+        self.starts[last] = positions.len();
+    }
+
+    /// Appends every point in the cells touched by the sphere around
+    /// `position`, in index order. Callers still check the distance.
+    pub fn query_into(&self, position: Vector3<f32>, radius: f32, found: &mut Vec<usize>) {
+        let offset = Vector3::new(radius, radius, radius);
+        let low = self.coordinates(position - offset);
+        let high = self.coordinates(position + offset);
+        let start = found.len();
+        for z in low.2..=high.2 {
+            for y in low.1..=high.1 {
+                for x in low.0..=high.0 {
+                    let cell = self.index((x, y, z));
+                    found
+                        .extend_from_slice(&self.entries[self.starts[cell]..self.starts[cell + 1]]);
+                }
+            }
+        }
+        found[start..].sort_unstable();
+    }
+}
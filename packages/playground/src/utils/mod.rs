@@ -1,3 +1,8 @@
 pub use coordinate_encoder::{CoordinateEncoder, FlatEncoder, LoopingEncoder, ScreenSpaceEncoder};
+pub use random::SplitMix64;
+pub use vector::{limit, set_magnitude};
 
 mod coordinate_encoder;
+mod random;
+pub mod thread_pool;
+mod vector;
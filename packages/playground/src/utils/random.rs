@@ -0,0 +1,47 @@
+use rand::{Error, RngCore};
+
+/// Small splitmix64 generator. Seeding one from the simulation seed, the step
+/// and an entity index gives every entity its own stream, so results don't
+/// depend on the order or the thread entities are processed on.
+#[derive(Debug, Copy, Clone)]
+pub struct SplitMix64 {
+    state: u64,
+}
+
+impl SplitMix64 {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    pub fn for_entity(seed: u64, step: u64, index: usize) -> Self {
+        let mut rng = Self::new(seed ^ step.wrapping_mul(0xD1B5_4A32_D192_ED03));
+        rng.state ^= rng.next_u64() ^ (index as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+        rng
+    }
+}
+
+impl RngCore for SplitMix64 {
+    fn next_u32(&mut self) -> u32 {
+        (self.next_u64() >> 32) as u32
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
@@ -0,0 +1,16 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// natively rayon starts its global pool on first use, on the web the workers
+// behind it have to be started from JS first
+static READY: AtomicBool = AtomicBool::new(cfg!(not(target_arch = "wasm32")));
+
+/// Whether rayon's global thread pool can take work.
+pub fn is_ready() -> bool {
+    cfg!(feature = "parallel") && READY.load(Ordering::Acquire)
+}
+
+/// Records that the web workers behind the thread pool are running.
+#[cfg(feature = "wasm-threads")]
+pub fn mark_ready() {
+    READY.store(true, Ordering::Release);
+}
@@ -0,0 +1,21 @@
+use cgmath::prelude::*;
+
+/// `vec` shortened to `max` if it's any longer. Works in any vector space so
+/// the 2D and 3D simulations share it.
+pub fn limit<V: InnerSpace<Scalar = f32>>(vec: V, max: f32) -> V {
+    if vec.magnitude() > max {
+        set_magnitude(vec, max)
+    } else {
+        vec
+    }
+}
+
+/// `vec` scaled to `magnitude`, or zero when it has no direction.
+pub fn set_magnitude<V: InnerSpace<Scalar = f32>>(vec: V, magnitude: f32) -> V {
+    let current = vec.magnitude();
+    if current != 0.0 {
+        vec * (magnitude / current)
+    } else {
+        V::zero()
+    }
+}
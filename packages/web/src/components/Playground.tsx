@@ -1,5 +1,14 @@
 import { Accessor, Component, createSignal, onMount } from 'solid-js';
 import init, { FolioClient } from '@greym0uth/playground';
+import * as wasm from '@greym0uth/playground';
+
+// Only builds with the wasm-threads feature export this.
+type StartThreadPool = (threads: number) => Promise<void>;
+
+// Threads share memory through a SharedArrayBuffer, which browsers only hand
+// to cross origin isolated pages.
+const canUseThreads = () =>
+  typeof SharedArrayBuffer !== 'undefined' && window.crossOriginIsolated === true;
 
 declare module 'solid-js' {
   namespace JSX {
@@ -31,6 +40,11 @@ function createPlayground() {
       const gl = canvas.getContext('webgl');
       const client = new FolioClient(gl!, accessor());
 
+      const { startThreadPool } = wasm as unknown as { startThreadPool?: StartThreadPool };
+      if (startThreadPool && canUseThreads()) {
+        startThreadPool(navigator.hardwareConcurrency).then(() => client.set_parallel(true));
+      }
+
       const render = () => {
           client.update();
           client.render();
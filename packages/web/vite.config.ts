@@ -4,6 +4,13 @@ import wasmPack from 'vite-plugin-wasm-pack';
 
 export default defineConfig({
   plugins: [solidPlugin(), wasmPack([], ['@greym0uth/playground'])],
+  server: {
+    // cross origin isolation, so the playground can share memory between threads
+    headers: {
+      'Cross-Origin-Opener-Policy': 'same-origin',
+      'Cross-Origin-Embedder-Policy': 'require-corp',
+    },
+  },
   build: {
     target: 'esnext',
     polyfillDynamicImport: false,